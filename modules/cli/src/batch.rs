@@ -0,0 +1,366 @@
+use crate::command::{BatchCommand, EBCommand, GetCommand, HLSCommand};
+use crate::{handle_eb, handle_get, handle_hls};
+use clap::Parser;
+use futures::{stream, StreamExt};
+use saidl_helper::error::{Error, Result};
+use saidl_helper::file::{io_error, overwrite_data_file};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+struct BatchFile {
+    jobs: Vec<BatchJob>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum JobKind {
+    Hls,
+    Eb,
+    Get,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchJob {
+    #[serde(rename = "type")]
+    kind: JobKind,
+
+    // Playlist or file url, or the config file of an ebook
+    input: String,
+
+    // Key of the job in the status file, the type, input and output by default
+    name: Option<String>,
+    headers: Option<PathBuf>,
+    output: Option<String>,
+
+    // Other arguments of the subcommand, such as ["--retry", "3"]
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+enum Task {
+    Hls(Box<HLSCommand>),
+    Eb(EBCommand),
+    Get(GetCommand),
+}
+
+impl BatchJob {
+    fn key(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let kind = match self.kind {
+            JobKind::Hls => "hls",
+            JobKind::Eb => "eb",
+            JobKind::Get => "get",
+        };
+        match &self.output {
+            Some(output) => format!("{} {} {}", kind, self.input, output),
+            None => format!("{} {}", kind, self.input),
+        }
+    }
+
+    // Arguments are checked by the subcommand parser before any job starts
+    fn into_task(self, key: &str) -> Result<Task> {
+        let invalid = |e: String| Error::Parse(format!("Invalid job {}: {}", key, e));
+        let mut args: Vec<OsString> = vec!["-i".into(), self.input.into()];
+        if let Some(headers) = self.headers {
+            args.extend(["-H".into(), headers.into()]);
+        }
+        if let Some(output) = self.output {
+            if let JobKind::Eb = self.kind {
+                return Err(invalid("the book name is set by its config".to_string()));
+            }
+            args.extend(["-o".into(), output.into()]);
+        }
+        args.extend(self.options.into_iter().map(OsString::from));
+        match self.kind {
+            JobKind::Hls => HLSCommand::try_parse_from(with_name("hls", args))
+                .map(|hls| Task::Hls(Box::new(hls))),
+            JobKind::Eb => EBCommand::try_parse_from(with_name("eb", args)).map(Task::Eb),
+            JobKind::Get => GetCommand::try_parse_from(with_name("get", args)).map(Task::Get),
+        }
+        .map_err(|e| invalid(e.to_string().trim().to_string()))
+    }
+}
+
+fn with_name(name: &str, args: Vec<OsString>) -> Vec<OsString> {
+    std::iter::once(OsString::from(name)).chain(args).collect()
+}
+
+impl Task {
+    async fn run(self) -> Result<()> {
+        match self {
+            Task::Hls(hls) => handle_hls(*hls).await,
+            Task::Eb(eb) => handle_eb(eb).await,
+            Task::Get(get) => handle_get(get).await,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JobResult {
+    state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Result of each job by its key, kept next to the job list
+#[derive(Serialize, Deserialize, Default)]
+struct BatchStatus {
+    #[serde(default)]
+    jobs: BTreeMap<String, JobResult>,
+}
+
+impl BatchStatus {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content =
+            fs::read_to_string(path).map_err(|e| io_error("Cannot read batch status", path, e))?;
+        toml::from_str(&content)
+            .map_err(|e| Error::Parse(format!("Invalid batch status {}: {}", path.display(), e)))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self)
+            .map_err(|e| Error::Parse(format!("Cannot write batch status: {}", e)))?;
+        let dir = path.parent().and_then(|p| p.to_str()).unwrap_or_default();
+        let dir = if dir.is_empty() { "." } else { dir };
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .expect("Status file has a name");
+        overwrite_data_file(content.as_bytes(), dir, name)
+    }
+
+    fn is_completed(&self, key: &str) -> bool {
+        self.jobs
+            .get(key)
+            .is_some_and(|r| r.state == JobState::Completed)
+    }
+}
+
+fn status_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".status.toml");
+    path.with_file_name(name)
+}
+
+// Jobs of a TOML file, or a JSON file with the same layout
+fn load_jobs(path: &Path) -> Result<Vec<(String, Task)>> {
+    let content =
+        fs::read_to_string(path).map_err(|e| io_error("Cannot read job list", path, e))?;
+    let is_json = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+    let file: BatchFile = if is_json {
+        serde_json::from_str(&content).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&content).map_err(|e| e.to_string())
+    }
+    .map_err(|e| Error::Parse(format!("Invalid job list {}: {}", path.display(), e)))?;
+
+    let mut keys = HashSet::new();
+    file.jobs
+        .into_iter()
+        .map(|job| {
+            let key = job.key();
+            if !keys.insert(key.clone()) {
+                return Err(Error::Parse(format!(
+                    "Duplicate job {} in {}, set different names",
+                    key,
+                    path.display()
+                )));
+            }
+            let task = job.into_task(&key)?;
+            Ok((key, task))
+        })
+        .collect()
+}
+
+pub async fn handle_batch(batch: BatchCommand) -> Result<()> {
+    let jobs = load_jobs(&batch.input)?;
+    let status_path = status_path(&batch.input);
+    let mut status = BatchStatus::load(&status_path)?;
+
+    let total = jobs.len();
+    let pending: Vec<_> = jobs
+        .into_iter()
+        .filter(|(key, _)| batch.all || !status.is_completed(key))
+        .collect();
+    if pending.len() < total {
+        println!("Skip {} completed jobs", total - pending.len());
+    }
+
+    let mut results = stream::iter(pending)
+        .map(|(key, task)| async move {
+            println!("Start job {}", key);
+            let result = task.run().await;
+            (key, result)
+        })
+        .buffer_unordered(batch.jobs as usize);
+    let mut errors = Vec::new();
+    while let Some((key, result)) = results.next().await {
+        let job_result = match result {
+            Ok(()) => {
+                println!("Job {} completed", key);
+                JobResult {
+                    state: JobState::Completed,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                println!("Job {} failed: {}", key, e);
+                let job_result = JobResult {
+                    state: JobState::Failed,
+                    error: Some(e.to_string()),
+                };
+                errors.push(e);
+                job_result
+            }
+        };
+        // Saved after each job, so an interrupted batch keeps the finished ones
+        status.jobs.insert(key, job_result);
+        status.save(&status_path)?;
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "{} of {} jobs failed, run the batch again to retry them",
+        errors.len(),
+        total
+    );
+    Err(errors.swap_remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{handle_batch, load_jobs, status_path, BatchStatus, JobState, Task};
+    use crate::command::BatchCommand;
+    use saidl_helper::test_util::{http_response, test_dir, TestServer};
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Reply with the same small file to every request
+    async fn serve_text(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let server = TestServer::serve(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            http_response("200 OK", "", body.as_bytes())
+        })
+        .await;
+        (format!("{}/notes.txt", server.url), count)
+    }
+
+    #[test]
+    fn load_jobs_work() {
+        let dir = test_dir("batch-load");
+        let toml_path = dir.join("jobs.toml");
+        fs::write(
+            &toml_path,
+            r#"
+            [[jobs]]
+            type = "hls"
+            input = "https://example.com/a.m3u8"
+            output = "a"
+            options = ["--retry", "3", "--mp4"]
+
+            [[jobs]]
+            type = "eb"
+            name = "novel"
+            input = "novel.toml"
+            "#,
+        )
+        .unwrap();
+        let jobs = load_jobs(&toml_path).unwrap();
+        assert_eq!(jobs[0].0, "hls https://example.com/a.m3u8 a");
+        match &jobs[0].1 {
+            Task::Hls(hls) => assert!(hls.mp4 && hls.retry == Some(3)),
+            _ => panic!("Expect hls job"),
+        }
+        assert_eq!(jobs[1].0, "novel");
+        assert!(matches!(jobs[1].1, Task::Eb(_)));
+
+        let json_path = dir.join("jobs.json");
+        fs::write(
+            &json_path,
+            r#"{"jobs": [{"type": "get", "input": "https://example.com/f.zip", "options": ["-c", "4"]}]}"#,
+        )
+        .unwrap();
+        match &load_jobs(&json_path).unwrap()[0].1 {
+            Task::Get(get) => assert_eq!(get.connections, 4),
+            _ => panic!("Expect get job"),
+        }
+
+        // Arguments are checked before running
+        fs::write(
+            &json_path,
+            r#"{"jobs": [{"type": "hls", "input": "a.m3u8", "options": ["--unknown"]}]}"#,
+        )
+        .unwrap();
+        assert!(load_jobs(&json_path).is_err());
+        fs::write(
+            &json_path,
+            r#"{"jobs": [{"type": "eb", "input": "a.toml", "output": "b"}]}"#,
+        )
+        .unwrap();
+        assert!(load_jobs(&json_path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_failed_jobs_work() {
+        let (url, count) = serve_text("hello").await;
+        let dir = test_dir("batch-retry");
+        let output = dir.join("notes.txt");
+        let book = dir.join("book.toml");
+        let jobs_path = dir.join("jobs.toml");
+        fs::write(
+            &jobs_path,
+            format!(
+                "[[jobs]]\ntype = \"get\"\nname = \"notes\"\ninput = \"{}\"\noutput = {:?}\n\n\
+                [[jobs]]\ntype = \"eb\"\nname = \"book\"\ninput = {:?}\n",
+                url,
+                output.to_str().unwrap(),
+                book.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+        let batch = || BatchCommand {
+            input: jobs_path.clone(),
+            jobs: 2,
+            all: false,
+        };
+
+        // Config of the book is missing
+        assert!(handle_batch(batch()).await.is_err());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "hello");
+        let status = BatchStatus::load(&status_path(&jobs_path)).unwrap();
+        assert_eq!(status.jobs["notes"].state, JobState::Completed);
+        assert_eq!(status.jobs["book"].state, JobState::Failed);
+        assert!(status.jobs["book"].error.is_some());
+
+        // Only the failed job runs again
+        let requests = count.load(Ordering::SeqCst);
+        assert!(handle_batch(batch()).await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), requests);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
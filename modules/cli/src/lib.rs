@@ -1,127 +1,326 @@
+mod batch;
 mod command;
 
-use crate::command::{Cli, Commands, EBCommand, HLSCommand};
+use crate::batch::handle_batch;
+use crate::command::{Cli, Commands, EBCommand, GetCommand, HLSCommand};
 use clap::Parser;
 use saidl_ebook::{
     Config, EBConfig, EbookFlow, IterDownloader, NumDownloader, StandardEpub, TocDownloader,
     WriteBook,
 };
+use saidl_get::GetConfig;
 use saidl_helper::{
-    file::get_lines,
-    http::{lines_to_header, HeaderMap},
+    cookie::CookieJar,
+    error::{Error, Result},
+    file::{get_raw_file_content, io_error},
+    http::{lines_to_header, HeaderMap, HttpClient},
+    import::{load_curl, load_har, ImportedRequest},
+    retry::RetryPolicy,
 };
-use saidl_hls::download;
-use std::path::PathBuf;
+use saidl_hls::{
+    crypto::read_key_file,
+    download,
+    live::record_live,
+    load_playlist,
+    playlist::{har_media, VariantFilter},
+    pool::DEFAULT_JOBS,
+    resume, HLSConfig,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-pub async fn run() {
+// Run the command and return the process exit code
+pub async fn run() -> i32 {
     let cli: Cli = Cli::parse();
     let command = cli
         .command
         .expect("Invalid commands is already handled by clap");
-    match command {
-        Commands::HLS(hls) => {
-            handle_hls(hls).await;
-        }
-        Commands::EB(eb) => {
-            handle_eb(eb).await;
+    let result = match command {
+        Commands::HLS(hls) => handle_hls(*hls).await,
+        Commands::EB(eb) => handle_eb(eb).await,
+        Commands::GET(get) => handle_get(get).await,
+        Commands::BATCH(batch) => handle_batch(batch).await,
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            e.exit_code()
         }
     }
 }
 
-pub async fn handle_hls(hls: HLSCommand) {
-    match hls.input {
-        None => {
-            println!("Input file is required");
+pub async fn handle_hls(hls: HLSCommand) -> Result<()> {
+    // Request copied from the browser, its url is the input unless --input is set
+    let (request, link_list) = match (&hls.curl, &hls.har) {
+        (Some(path), _) => (Some(load_curl(path)?), None),
+        (_, Some(path)) => match har_media(load_har(path)?) {
+            Some((request, link_list)) => (Some(request), link_list),
+            None => {
+                return Err(Error::Parse(format!(
+                    "No playlist or segment request in {}",
+                    path.display()
+                )))
+            }
+        },
+        _ => (None, None),
+    };
+    let input = hls.input.or_else(|| {
+        request
+            .as_ref()
+            .filter(|_| link_list.is_none())
+            .map(|r| r.url.clone())
+    });
+
+    // Extract headers from header file
+    let headers = extract_header(hls.headers)?;
+    let cookies = load_cookies(&hls.cookies)?;
+    let (headers, cookies) = apply_imported_request(request, headers, cookies)?;
+    let client = HttpClient::build(&headers, hls.h2, cookies.clone(), hls.proxy.as_deref())?
+        .with_rate_limit(hls.rate);
+    let key = match hls.key_file {
+        Some(path) => Some(read_key_file(&path)?),
+        None => hls.key_hex,
+    };
+    let config = HLSConfig {
+        png: hls.png,
+        jobs: match (hls.jobs, hls.multi_thread) {
+            (Some(jobs), _) => jobs as usize,
+            (None, true) => DEFAULT_JOBS,
+            (None, false) => 1,
+        },
+        host_jobs: hls.host_jobs.map(|n| n as usize),
+        keep: hls.keep,
+        client: &client,
+        output: hls.output,
+        delay: hls.delay,
+        retry: RetryPolicy::build(
+            hls.retry.unwrap_or_default(),
+            hls.retry_delay,
+            hls.retry_max_delay,
+        ),
+        variant: VariantFilter {
+            lowest_bandwidth: hls.lowest_bandwidth,
+            resolution: hls.resolution,
+            max_height: hls.max_height,
+            codec: hls.codec,
+        },
+        key,
+        ffmpeg: hls.ffmpeg,
+        mp4: hls.mp4,
+    };
+
+    let result = async {
+        if let Some(dir) = hls.resume {
+            return resume(&dir, config).await;
         }
-        Some(path) => {
-            // Extract links from input
-            let content_lines = get_lines(path);
-            let links = link_filter(content_lines.into_iter());
-
-            // Extract headers from header file
-            let headers = extract_header(hls.headers);
-
-            download(
-                &links,
-                hls.png,
-                hls.h2,
-                hls.multi_thread,
-                hls.keep,
-                &headers,
-                hls.output,
-                hls.delay,
-                hls.retry,
-            )
-            .await;
+        let input = match (input, link_list) {
+            (Some(input), _) => input,
+            // Segments captured in the HAR file
+            (None, Some(playlist)) => return download(&playlist, config).await,
+            (None, None) => unreachable!("Input is required by clap without --resume"),
+        };
+
+        if hls.live {
+            return record_live(&input, hls.base_url.as_deref(), config, hls.live_duration).await;
         }
+
+        // Extract segment links from input
+        let playlist = match load_playlist(&input, hls.base_url.as_deref(), &config).await {
+            Ok(playlist) => playlist,
+            Err(e) => {
+                eprintln!("Cannot load playlist {}", input);
+                return Err(e);
+            }
+        };
+        download(&playlist, config).await
     }
+    .await;
+    // Cookies are saved even if the download failed, so a later --resume keeps the session
+    save_cookies(&cookies, &hls.cookies, hls.save_cookies)?;
+    result
 }
 
-pub async fn handle_eb(eb: EBCommand) {
-    match eb.input {
-        None => {
-            println!("Input file is required");
+pub async fn handle_eb(eb: EBCommand) -> Result<()> {
+    let path = eb.input;
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| io_error("Cannot read config", &path, e))?;
+    let config: Config = toml::from_str(&contents)
+        .map_err(|e| Error::Parse(format!("Invalid config {}: {}", path.display(), e)))?;
+    let Config {
+        title_selector,
+        content_selector,
+        content_mode,
+        delay,
+        retry,
+        rate,
+        proxy,
+        flow,
+        name,
+    } = config;
+    let request = match (&eb.curl, &eb.har) {
+        (Some(path), _) => Some(load_curl(path)?),
+        (_, Some(path)) => Some(page_request(load_har(path)?, path)?),
+        _ => None,
+    };
+    let headers = extract_header(eb.headers)?;
+    let cookies = load_cookies(&eb.cookies)?;
+    let (headers, cookies) = apply_imported_request(request, headers, cookies)?;
+    let client = HttpClient::build(
+        &headers,
+        eb.h2,
+        cookies.clone(),
+        eb.proxy.as_deref().or(proxy.as_deref()),
+    )?
+    .with_rate_limit(eb.rate.or(rate));
+    let cli_config = EBConfig {
+        title_selector,
+        content_selector,
+        content_mode,
+        client: &client,
+        delay,
+        retry,
+    };
+    let content = match flow {
+        EbookFlow::Iter(f) => {
+            let downloader = IterDownloader::build(f);
+            downloader.download(cli_config).await
         }
-        Some(path) => {
-            println!("{}", eb.h2);
-            let contents = std::fs::read_to_string(path).unwrap();
-            let config: Config = toml::from_str(&contents).unwrap();
-            let Config {
-                title_selector,
-                content_selector,
-                delay,
-                retry,
-                flow,
-                name,
-            } = config;
-            let headers = extract_header(eb.headers);
-            let cli_config = EBConfig {
-                title_selector,
-                content_selector,
-                h2: eb.h2,
-                headers: &headers,
-                delay,
-                retry,
-            };
-            let content = match flow {
-                EbookFlow::Iter(f) => {
-                    let downloader = IterDownloader::build(f);
-                    downloader.download(cli_config).await
-                }
-                EbookFlow::Toc(f) => {
-                    let downloader = TocDownloader::build(f);
-                    downloader.download(cli_config).await
-                }
-                EbookFlow::Num(f) => {
-                    let downloader = NumDownloader::build(f);
-                    downloader.download(cli_config).await
-                }
-            };
-            let writer = StandardEpub::build(name.clone(), content);
-            writer.write(eb.chapter_num).unwrap();
+        EbookFlow::Toc(f) => {
+            let downloader = TocDownloader::build(f);
+            downloader.download(cli_config).await
         }
+        EbookFlow::Num(f) => {
+            let downloader = NumDownloader::build(f);
+            downloader.download(cli_config).await
+        }
+    };
+    save_cookies(&cookies, &eb.cookies, eb.save_cookies)?;
+    let writer = StandardEpub::build(name, content?);
+    writer.write(eb.chapter_num)
+}
+
+pub async fn handle_get(get: GetCommand) -> Result<()> {
+    let headers = extract_header(get.headers)?;
+    let cookies = load_cookies(&get.cookies)?;
+    let client = HttpClient::build(&headers, get.h2, cookies.clone(), get.proxy.as_deref())?
+        .with_rate_limit(get.rate);
+    let config = GetConfig {
+        client: &client,
+        output: get.output,
+        connections: get.connections as usize,
+        delay: get.delay,
+        retry: RetryPolicy::build(
+            get.retry.unwrap_or_default(),
+            get.retry_delay,
+            get.retry_max_delay,
+        ),
+        checksum: get.checksum,
+    };
+    let result = saidl_get::download(&get.input, config).await;
+    save_cookies(&cookies, &get.cookies, get.save_cookies)?;
+    result.map(|_| ())
+}
+
+// Headers of the header file win over the imported ones,
+// imported cookies are added to the cookie jar
+fn apply_imported_request(
+    request: Option<ImportedRequest>,
+    headers: Option<HeaderMap>,
+    cookies: Option<Arc<CookieJar>>,
+) -> Result<(Option<HeaderMap>, Option<Arc<CookieJar>>)> {
+    let request = match request {
+        None => return Ok((headers, cookies)),
+        Some(r) => r,
+    };
+    if request.method != "GET" {
+        println!(
+            "Only GET requests are sent, method {} of {} is ignored",
+            request.method, request.url
+        );
+    }
+    let mut imported_headers = request.headers;
+    imported_headers.extend(headers.unwrap_or_default());
+    if request.cookies.is_empty() {
+        return Ok((Some(imported_headers), cookies));
     }
+    let cookies = cookies.unwrap_or_default();
+    cookies.add(&request.url, &request.cookies)?;
+    Ok((Some(imported_headers), Some(cookies)))
 }
 
-fn link_filter(links: impl Iterator<Item = String>) -> Vec<String> {
-    links.filter(|i| i.starts_with("http")).collect()
+// The html page request of a HAR capture, or its first request
+fn page_request(mut requests: Vec<ImportedRequest>, path: &Path) -> Result<ImportedRequest> {
+    if requests.is_empty() {
+        return Err(Error::Parse(format!("No request in {}", path.display())));
+    }
+    let index = requests
+        .iter()
+        .position(|r| {
+            r.mime_type
+                .as_deref()
+                .is_some_and(|m| m.starts_with("text/html"))
+        })
+        .unwrap_or(0);
+    Ok(requests.swap_remove(index))
 }
 
-fn extract_header(path: Option<PathBuf>) -> Option<HeaderMap> {
+// A missing cookie file starts an empty jar, which can be saved to it later
+fn load_cookies(path: &Option<PathBuf>) -> Result<Option<Arc<CookieJar>>> {
     match path {
-        None => None,
+        None => Ok(None),
+        Some(p) if !p.exists() => Ok(Some(Arc::new(CookieJar::default()))),
+        Some(p) => Ok(Some(Arc::new(CookieJar::load(p)?))),
+    }
+}
+
+fn save_cookies(
+    cookies: &Option<Arc<CookieJar>>,
+    path: &Option<PathBuf>,
+    save: bool,
+) -> Result<()> {
+    match (cookies, path) {
+        (Some(cookies), Some(p)) if save => cookies.save(p),
+        _ => Ok(()),
+    }
+}
+
+fn extract_header(path: Option<PathBuf>) -> Result<Option<HeaderMap>> {
+    match path {
+        None => Ok(None),
         Some(p) => {
-            let header_lines = get_lines(p);
-            Some(lines_to_header(header_lines.into_iter()))
+            let content = get_raw_file_content(p.clone())?;
+            let headers =
+                lines_to_header(content.lines().map(String::from)).map_err(|e| match e {
+                    Error::Parse(reason) => {
+                        Error::Parse(format!("Invalid header file {}: {}", p.display(), reason))
+                    }
+                    e => e,
+                })?;
+            Ok(Some(headers))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use saidl_helper::test_util::test_dir;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[tokio::test]
+    async fn playlist_load_error_work() {
+        let dir = test_dir("cli-playlist");
+        let input = dir.join("missing.m3u8");
+        let hls = HLSCommand::try_parse_from(["hls", "-i", input.to_str().unwrap()]).unwrap();
+        let error = handle_hls(hls).await.unwrap_err();
+        assert!(matches!(error, Error::Io(_)));
+        assert_ne!(error.exit_code(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
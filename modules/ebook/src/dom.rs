@@ -1,80 +1,224 @@
-use crate::Chapter;
-use scraper::{ElementRef, Html, Selector};
+use crate::xhtml::{escape_xhtml, is_dropped, write_html};
+use crate::{Chapter, ContentMode};
+use saidl_helper::error::{Error, Result};
+use saidl_helper::http::Url;
+use scraper::{ElementRef, Html, Node, Selector};
 use select::document::Document;
 use select::predicate::Name;
 
-const CURRENT_URLS: [&'static str; 2] = ["javascript:void(0);", "#"];
+const CURRENT_URLS: [&str; 2] = ["javascript:void(0);", "#"];
+
+// Elements ending a line in the text content mode
+const BLOCK_ELEMENTS: [&str; 16] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "blockquote",
+    "ul",
+    "ol",
+    "li",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "tr",
+];
 
 pub fn get_dom(raw_html: &str) -> Html {
-    return Html::parse_document(raw_html);
+    Html::parse_document(raw_html)
+}
+
+// Chapter link of a table of content, its text is the fallback title
+pub struct TocLink {
+    pub url: String,
+    pub title: String,
+}
+
+impl TocLink {
+    fn build(href: &str, text: &str, void_sub: &str, base: &Url) -> Self {
+        let url = if CURRENT_URLS.contains(&href.trim()) {
+            resolve_url(void_sub, base)
+        } else {
+            resolve_url(href, base)
+        };
+        let title = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        Self { url, title }
+    }
+}
+
+// Base of relative links: relative_base of the config, <base href> of the page or the page url
+pub fn page_base(document: &Html, page_url: &Url, relative_base: Option<&Url>) -> Url {
+    if let Some(base) = relative_base {
+        return base.clone();
+    }
+    let selector = Selector::parse("base[href]").expect("Base selector is valid");
+    document
+        .select(&selector)
+        .next()
+        .and_then(|element| element.value().attr("href"))
+        .and_then(|href| page_url.join(href.trim()).ok())
+        .unwrap_or_else(|| page_url.clone())
+}
+
+// Absolute url of a href, kept as it is when it cannot be joined
+pub fn resolve_url(href: &str, base: &Url) -> String {
+    match base.join(href.trim()) {
+        Ok(url) => url.to_string(),
+        Err(_) => href.to_string(),
+    }
 }
 
-pub fn get_all_urls(raw_html: &str, void_sub: &str) -> Vec<String> {
+pub fn get_all_urls(raw_html: &str, void_sub: &str, base: &Url) -> Vec<TocLink> {
     Document::from(raw_html)
         .find(Name("a"))
-        .filter_map(|n| n.attr("href"))
-        .map({
-            |x| {
-                if CURRENT_URLS.contains(&x.trim()) {
-                    print!("{x}");
-                    return String::from(void_sub);
-                }
-                String::from(x)
-            }
+        .filter_map(|n| {
+            n.attr("href")
+                .map(|href| TocLink::build(href, &n.text(), void_sub, base))
         })
         .collect()
 }
 
-pub fn get_text_from_selector(document: &Html, selector: &str) -> String {
+// Anchors inside the first element matching the toc selector, in document order
+pub fn get_toc_links(
+    document: &Html,
+    toc_selector: &str,
+    void_sub: &str,
+    base: &Url,
+) -> Result<Option<Vec<TocLink>>> {
+    let anchor_selector = parse_selector("a[href]")?;
+    let links = get_first_selection(document, toc_selector)?.map(|toc| {
+        toc.select(&anchor_selector)
+            .filter_map(|a| {
+                let href = a.value().attr("href")?;
+                Some(TocLink::build(
+                    href,
+                    &a.text().collect::<String>(),
+                    void_sub,
+                    base,
+                ))
+            })
+            .collect()
+    });
+    Ok(links)
+}
+
+pub fn get_text_from_selector(document: &Html, selector: &str) -> Result<String> {
     let mut result = String::new();
-    match get_first_selection(&document, selector) {
+    match get_first_selection(document, selector)? {
         Some(items) => {
             for item in items.text() {
                 result.push_str(item);
             }
         }
         None => {
-            return "".to_string();
+            return Ok("".to_string());
         }
     }
 
-    return result;
+    Ok(result)
+}
+
+// Content as an XHTML fragment, empty when no element matches
+pub fn get_content_from_selector(
+    document: &Html,
+    selector: &str,
+    mode: ContentMode,
+) -> Result<String> {
+    let element = match get_first_selection(document, selector)? {
+        Some(element) => element,
+        None => return Ok(String::new()),
+    };
+    let content = match mode {
+        ContentMode::Html => {
+            let mut html = String::new();
+            write_html(element, &mut html);
+            html.trim().to_string()
+        }
+        ContentMode::Text => {
+            let mut text = String::new();
+            write_text(element, &mut text);
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(|line| format!("<p>{}</p>", escape_xhtml(line)))
+                .collect()
+        }
+    };
+    Ok(content)
 }
 
-pub fn get_url_from_selector(document: &Html, selector: &str) -> Option<String> {
-    let first_select_result = get_first_selection(&document, selector).unwrap();
-    let url = first_select_result.value().attr("href");
-    match url {
-        Some(u) => Some(u.to_string()),
-        None => None,
+// Text of the element with a line for each line break and block element
+fn write_text(element: ElementRef, output: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => output.push_str(text),
+            Node::Element(e) if is_dropped(e) => {}
+            Node::Element(e) if e.name() == "br" => output.push('\n'),
+            Node::Element(e) => {
+                let block = BLOCK_ELEMENTS.contains(&e.name());
+                if block {
+                    output.push('\n');
+                }
+                write_text(ElementRef::wrap(child).expect("Node is an element"), output);
+                if block {
+                    output.push('\n');
+                }
+            }
+            _ => {}
+        }
     }
 }
 
-fn get_first_selection<'a>(document: &'a Html, selector: &str) -> Option<ElementRef<'a>> {
-    let parsed_selector = Selector::parse(selector).unwrap();
-    document.select(&parsed_selector).next()
+// None when no element matches or the element has no href
+pub fn get_url_from_selector(
+    document: &Html,
+    selector: &str,
+    base: &Url,
+) -> Result<Option<String>> {
+    let url = get_first_selection(document, selector)?
+        .and_then(|element| element.value().attr("href"))
+        .map(|u| resolve_url(u, base));
+    Ok(url)
+}
+
+fn get_first_selection<'a>(document: &'a Html, selector: &str) -> Result<Option<ElementRef<'a>>> {
+    let parsed_selector = parse_selector(selector)?;
+    Ok(document.select(&parsed_selector).next())
+}
+
+pub fn parse_selector(selector: &str) -> Result<Selector> {
+    Selector::parse(selector)
+        .map_err(|e| Error::Selector(format!("Invalid selector {}: {:?}", selector, e)))
 }
 
 pub async fn single_page_extract(
     document: &Html,
     title_selector: &str,
     content_selector: &str,
-) -> Chapter<String> {
-    let title = get_text_from_selector(document, title_selector);
-    let content = get_text_from_selector(document, content_selector);
+    content_mode: ContentMode,
+) -> Result<Chapter<String>> {
+    let title = get_text_from_selector(document, title_selector)?;
+    let content = get_content_from_selector(document, content_selector, content_mode)?;
     if content.is_empty() {
         println!("Empty content at chapter {}", title);
     }
-    Chapter { title, content }
+    Ok(Chapter { title, content })
 }
 
 pub async fn single_page_extract_with_next_url(
     document: &Html,
     title_selector: &str,
     content_selector: &str,
+    content_mode: ContentMode,
     next_url_selector: &str,
-) -> (Chapter<String>, Option<String>) {
-    let page_content = single_page_extract(document, title_selector, content_selector).await;
-    let next_url = get_url_from_selector(document, next_url_selector);
-    (page_content, next_url)
+    base: &Url,
+) -> Result<(Chapter<String>, Option<String>)> {
+    let page_content =
+        single_page_extract(document, title_selector, content_selector, content_mode).await?;
+    let next_url = get_url_from_selector(document, next_url_selector, base)?;
+    Ok((page_content, next_url))
 }
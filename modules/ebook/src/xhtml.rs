@@ -0,0 +1,122 @@
+use scraper::node::Element;
+use scraper::{ElementRef, Html, Node};
+use xml::reader::{EventReader, XmlEvent};
+
+// Elements kept by the html content mode, other elements are replaced by their children
+const KEPT_ELEMENTS: [&str; 21] = [
+    "p",
+    "br",
+    "hr",
+    "em",
+    "strong",
+    "i",
+    "b",
+    "u",
+    "s",
+    "sub",
+    "sup",
+    "blockquote",
+    "ul",
+    "ol",
+    "li",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+];
+
+const VOID_ELEMENTS: [&str; 2] = ["br", "hr"];
+
+// Elements removed with their children in both content modes
+const DROPPED_ELEMENTS: [&str; 10] = [
+    "script", "style", "noscript", "iframe", "template", "svg", "form", "object", "embed", "ins",
+];
+
+// Scripts, styles, embedded frames and ad blocks
+pub fn is_dropped(element: &Element) -> bool {
+    DROPPED_ELEMENTS.contains(&element.name()) || is_ad_block(element)
+}
+
+// Class or id such as "ads", "ad-top", "adsbygoogle" or "advertisement"
+fn is_ad_block(element: &Element) -> bool {
+    element
+        .id()
+        .into_iter()
+        .chain(element.classes())
+        .any(|name| {
+            let name = name.to_ascii_lowercase();
+            name == "ad"
+                || name.starts_with("ad-")
+                || name.starts_with("ad_")
+                || name.starts_with("ads")
+                || name.contains("advert")
+                || name.contains("sponsor")
+        })
+}
+
+// Children of the element, attributes are dropped
+pub fn write_html(element: ElementRef, output: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => output.push_str(&escape_xhtml(text)),
+            Node::Element(e) if is_dropped(e) => {}
+            Node::Element(e) => {
+                let name = e.name();
+                let child = ElementRef::wrap(child).expect("Node is an element");
+                if VOID_ELEMENTS.contains(&name) {
+                    output.push_str(&format!("<{}/>", name));
+                } else if KEPT_ELEMENTS.contains(&name) {
+                    output.push_str(&format!("<{}>", name));
+                    write_html(child, output);
+                    output.push_str(&format!("</{}>", name));
+                } else {
+                    write_html(child, output);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Characters not allowed in XML are removed
+pub fn escape_xhtml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() || c == '\u{FFFE}' || c == '\u{FFFF}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Chapter document, the content is parsed again so stray tags and entities cannot break it
+pub fn content_to_xhtml(title: &str, content: &str) -> String {
+    let fragment = Html::parse_fragment(content);
+    let mut body = String::new();
+    write_html(fragment.root_element(), &mut body);
+    let title = escape_xhtml(title);
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+    <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="https://www.w3.org/ns/epub/2007/ops/"><head><title>{}</title></head><body>
+    <h1>{}</h1>{}</body></html>"#,
+        title, title, body
+    )
+}
+
+// Readers refuse a book with a chapter that is not well-formed XML
+pub fn check_xhtml(document: &str) -> Result<(), String> {
+    for event in EventReader::from_str(document) {
+        if let XmlEvent::EndDocument = event.map_err(|e| e.to_string())? {
+            break;
+        }
+    }
+    Ok(())
+}
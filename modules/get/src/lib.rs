@@ -0,0 +1,442 @@
+pub mod state;
+#[cfg(test)]
+mod test;
+
+use crate::state::{Chunk, GetState};
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_RANGE, ETAG, LAST_MODIFIED};
+use reqwest::{Response, StatusCode, Url};
+use saidl_helper::error::{Error, Result};
+use saidl_helper::file::io_error;
+use saidl_helper::http::{with_range, HttpClient};
+use saidl_helper::retry::RetryPolicy;
+use sha2::{Digest, Sha256, Sha512};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::time;
+
+// Smallest range worth its own connection
+pub const MIN_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Received bytes of a connection between two saves of the download state
+const SAVE_INTERVAL: u64 = 4 * 1024 * 1024;
+
+pub struct GetConfig<'a> {
+    pub client: &'a HttpClient,
+
+    // File name, taken from Content-Disposition or the url when not set
+    pub output: Option<String>,
+
+    // Parallel connections of a file whose server accepts ranges
+    pub connections: usize,
+    pub delay: Option<u64>,
+    pub retry: RetryPolicy,
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Checksum {
+    Sha256(String),
+    Sha512(String),
+}
+
+// Checksum in ALGORITHM:HEX format, such as sha256:9f86d0...
+pub fn parse_checksum(value: &str) -> std::result::Result<Checksum, String> {
+    let invalid = || {
+        format!(
+            "Invalid checksum: {}, expected sha256:HEX or sha512:HEX",
+            value
+        )
+    };
+    let (algorithm, digest) = value.split_once(':').ok_or_else(invalid)?;
+    let digest = digest.trim().to_ascii_lowercase();
+    let (checksum, length) = match algorithm.trim().to_ascii_lowercase().as_str() {
+        "sha256" => (Checksum::Sha256(digest.clone()), 64),
+        "sha512" => (Checksum::Sha512(digest.clone()), 128),
+        _ => return Err(invalid()),
+    };
+    if digest.len() != length || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    Ok(checksum)
+}
+
+// Download the url into the current folder and return the file path.
+// Data is written to <name>.part, a server accepting ranges gets parallel connections
+// and the progress is kept in <name>.part.toml, so running again resumes the download.
+pub async fn download(url: &str, config: GetConfig<'_>) -> Result<PathBuf> {
+    // The first byte tells whether ranges are accepted and the size of the file
+    let probe = match config
+        .client
+        .send_wrapped_request(url, &with_range(&None, 0, 1)?, config.delay, config.retry)
+        .await
+    {
+        Ok(probe) => Some(probe),
+        // An empty file has no first byte
+        Err(Error::HttpStatus { code: 416, .. }) => None,
+        Err(e) => return Err(e),
+    };
+    let (response, size) = match probe {
+        Some(probe) => match range_size(&probe) {
+            Some(size) => (probe, Some(size)),
+            None if probe.status() == StatusCode::OK => (probe, None),
+            // Partial content of unknown size is not the whole file, request it again
+            None => (request_whole(url, &config).await?, None),
+        },
+        None => (request_whole(url, &config).await?, None),
+    };
+    let name = match &config.output {
+        Some(output) => output.clone(),
+        None => file_name(response.headers(), url),
+    };
+    let path = PathBuf::from(&name);
+    let part_path = PathBuf::from(format!("{}.part", name));
+    let state_path = PathBuf::from(format!("{}.part.toml", name));
+
+    match size {
+        Some(size) => {
+            let validator = validator(response.headers());
+            // Finish the probe so its connection is reused
+            response.bytes().await?;
+            download_ranges(url, size, validator, &part_path, &state_path, &config).await?;
+        }
+        None => {
+            println!(
+                "Size of {} is unknown or ranges are not accepted, it cannot be resumed",
+                url
+            );
+            download_whole(response, &part_path).await?;
+        }
+    }
+
+    if let Some(checksum) = &config.checksum {
+        if let Err(e) = verify_checksum(&part_path, checksum) {
+            // Corrupted data would be resumed again, so start over next time
+            let _ = fs::remove_file(&part_path);
+            let _ = fs::remove_file(&state_path);
+            return Err(e);
+        }
+    }
+    fs::rename(&part_path, &path).map_err(|e| io_error("Cannot write file", &path, e))?;
+    if state_path.exists() {
+        fs::remove_file(&state_path)
+            .map_err(|e| io_error("Cannot remove download state", &state_path, e))?;
+    }
+    println!("Downloaded {}", path.display());
+    Ok(path)
+}
+
+async fn download_ranges(
+    url: &str,
+    size: u64,
+    validator: Option<String>,
+    part_path: &Path,
+    state_path: &Path,
+    config: &GetConfig<'_>,
+) -> Result<()> {
+    let state = match GetState::load(state_path) {
+        Ok(state) if part_path.exists() && state.matches(size, &validator) => {
+            println!(
+                "Resume {} from {}/{} bytes",
+                part_path.display(),
+                state.downloaded(),
+                size
+            );
+            state
+        }
+        _ => {
+            let state = GetState::build(url, size, validator, config.connections, MIN_CHUNK_SIZE);
+            let file = fs::File::create(part_path)
+                .map_err(|e| io_error("Cannot create file", part_path, e))?;
+            file.set_len(size)
+                .map_err(|e| io_error("Cannot write file", part_path, e))?;
+            state
+        }
+    };
+    state.save(state_path)?;
+
+    let state = Arc::new(Mutex::new(state));
+    let chunks: Vec<(usize, Chunk)> = {
+        let state = state.lock().expect("Download state lock is poisoned");
+        state.chunks.iter().cloned().enumerate().collect()
+    };
+    let tasks: Vec<_> = chunks
+        .into_iter()
+        .filter(|(_, chunk)| !chunk.is_completed())
+        .map(|(index, chunk)| {
+            let worker = ChunkWorker {
+                client: config.client.clone(),
+                url: url.to_string(),
+                delay: config.delay,
+                retry: config.retry,
+                part_path: part_path.to_path_buf(),
+                state_path: state_path.to_path_buf(),
+                state: state.clone(),
+            };
+            tokio::spawn(worker.download(index, chunk))
+        })
+        .collect();
+
+    let mut first_error = None;
+    for result in futures::future::join_all(tasks).await {
+        let result = match result {
+            Ok(r) => r,
+            Err(e) => Err(Error::Network(format!("Download task failed: {}", e))),
+        };
+        if let (Err(e), None) = (result, &first_error) {
+            first_error = Some(e);
+        }
+    }
+    let state = state.lock().expect("Download state lock is poisoned");
+    state.save(state_path)?;
+    match first_error {
+        Some(e) => {
+            println!(
+                "{}/{} bytes are downloaded, run again to resume",
+                state.downloaded(),
+                size
+            );
+            Err(e)
+        }
+        None => Ok(()),
+    }
+}
+
+// Download of one chunk, the state is updated as data is written
+struct ChunkWorker {
+    client: HttpClient,
+    url: String,
+    delay: Option<u64>,
+    retry: RetryPolicy,
+    part_path: PathBuf,
+    state_path: PathBuf,
+    state: Arc<Mutex<GetState>>,
+}
+
+impl ChunkWorker {
+    // Requests are retried by the client, a broken body is requested again from the received bytes
+    async fn download(self, index: usize, mut chunk: Chunk) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let offset = chunk.start + chunk.done;
+            let range = with_range(&None, offset, chunk.end - offset)?;
+            let response = self
+                .client
+                .send_wrapped_request(&self.url, &range, self.delay, self.retry)
+                .await?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                return Err(Error::Parse(format!(
+                    "Server ignored the range of {}",
+                    self.url
+                )));
+            }
+            match self.write_body(index, &mut chunk, response).await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_retryable() && attempt < self.retry.times as u32 => {
+                    let wait = self.retry.backoff(attempt);
+                    attempt += 1;
+                    println!(
+                        "{}, retry {}/{} in {:.1}s",
+                        e,
+                        attempt,
+                        self.retry.times,
+                        wait.as_secs_f64()
+                    );
+                    time::sleep(wait).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn write_body(
+        &self,
+        index: usize,
+        chunk: &mut Chunk,
+        mut response: Response,
+    ) -> Result<()> {
+        let offset = chunk.start + chunk.done;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.part_path)
+            .await
+            .map_err(|e| io_error("Cannot open file", &self.part_path, e))?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|e| io_error("Cannot write file", &self.part_path, e))?;
+
+        let mut unsaved = 0;
+        while let Some(data) = response.chunk().await? {
+            let remaining = (chunk.end - chunk.start - chunk.done) as usize;
+            let data = &data[..data.len().min(remaining)];
+            file.write_all(data)
+                .await
+                .map_err(|e| io_error("Cannot write file", &self.part_path, e))?;
+            chunk.done += data.len() as u64;
+            unsaved += data.len() as u64;
+            if unsaved >= SAVE_INTERVAL || chunk.is_completed() {
+                // Data must be on disk before the state says so
+                file.flush()
+                    .await
+                    .map_err(|e| io_error("Cannot write file", &self.part_path, e))?;
+                self.update_state(index, chunk)?;
+                unsaved = 0;
+            }
+            if chunk.is_completed() {
+                return Ok(());
+            }
+        }
+        file.flush()
+            .await
+            .map_err(|e| io_error("Cannot write file", &self.part_path, e))?;
+        self.update_state(index, chunk)?;
+        Err(Error::Network(format!(
+            "Response of {} ended at byte {} of {}",
+            self.url,
+            chunk.start + chunk.done,
+            chunk.end
+        )))
+    }
+
+    fn update_state(&self, index: usize, chunk: &Chunk) -> Result<()> {
+        let mut state = self.state.lock().expect("Download state lock is poisoned");
+        state.chunks[index] = chunk.clone();
+        println!("Downloaded {}/{} bytes", state.downloaded(), state.size);
+        state.save(&self.state_path)
+    }
+}
+
+async fn request_whole(url: &str, config: &GetConfig<'_>) -> Result<Response> {
+    config
+        .client
+        .send_wrapped_request(url, &None, config.delay, config.retry)
+        .await
+}
+
+async fn download_whole(mut response: Response, part_path: &Path) -> Result<()> {
+    let mut file = tokio::fs::File::create(part_path)
+        .await
+        .map_err(|e| io_error("Cannot create file", part_path, e))?;
+    while let Some(data) = response.chunk().await? {
+        file.write_all(&data)
+            .await
+            .map_err(|e| io_error("Cannot write file", part_path, e))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| io_error("Cannot write file", part_path, e))
+}
+
+// Size of the file from Content-Range of a partial response
+fn range_size(response: &Response) -> Option<u64> {
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    let content_range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (_, size) = content_range.rsplit_once('/')?;
+    size.trim().parse().ok()
+}
+
+fn validator(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+// Name of Content-Disposition, or else the last segment of the url path
+pub fn file_name(headers: &HeaderMap, url: &str) -> String {
+    let from_header = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_disposition_name);
+    let from_url = || {
+        Url::parse(url).ok().and_then(|u| {
+            u.path_segments()
+                .and_then(|mut s| s.next_back())
+                .and_then(|s| percent_decode_str(s).decode_utf8().ok())
+                .map(|s| s.to_string())
+        })
+    };
+    from_header
+        .or_else(from_url)
+        .map(|name| sanitize_file_name(&name))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "download".to_string())
+}
+
+// filename* of RFC 6266 wins over filename
+pub fn content_disposition_name(value: &str) -> Option<String> {
+    let mut name = None;
+    for parameter in value.split(';') {
+        let (key, value) = match parameter.split_once('=') {
+            Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match key.as_str() {
+            "filename*" => {
+                // Such as UTF-8''na%C3%AFve.txt, a malformed value falls back to filename
+                let decoded = value
+                    .splitn(3, '\'')
+                    .nth(2)
+                    .and_then(|encoded| percent_decode_str(encoded).decode_utf8().ok());
+                if let Some(decoded) = decoded {
+                    return Some(decoded.to_string());
+                }
+            }
+            "filename" => name = Some(value.trim_matches('"').to_string()),
+            _ => {}
+        }
+    }
+    name
+}
+
+// The name must not leave the current folder
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || ":*?\"<>|".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    match name.trim() {
+        "." | ".." => String::new(),
+        name => name.to_string(),
+    }
+}
+
+fn verify_checksum(path: &Path, checksum: &Checksum) -> Result<()> {
+    let mut file = fs::File::open(path).map_err(|e| io_error("Cannot open file", path, e))?;
+    let (actual, expected) = match checksum {
+        Checksum::Sha256(expected) => {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut file, &mut hasher)
+                .map_err(|e| io_error("Cannot read file", path, e))?;
+            (hex::encode(hasher.finalize()), expected)
+        }
+        Checksum::Sha512(expected) => {
+            let mut hasher = Sha512::new();
+            std::io::copy(&mut file, &mut hasher)
+                .map_err(|e| io_error("Cannot read file", path, e))?;
+            (hex::encode(hasher.finalize()), expected)
+        }
+    };
+    if &actual != expected {
+        return Err(Error::Parse(format!(
+            "Checksum mismatch of {}: expected {}, got {}",
+            path.display(),
+            expected,
+            actual
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use saidl_helper::error::{Error, Result};
+use saidl_helper::file::{io_error, overwrite_data_file};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// Byte range of the file downloaded by one connection, end is exclusive
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub start: u64,
+    pub end: u64,
+    pub done: u64,
+}
+
+impl Chunk {
+    pub fn is_completed(&self) -> bool {
+        self.start + self.done >= self.end
+    }
+}
+
+// Progress of a download kept next to its .part file so it can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetState {
+    pub url: String,
+    pub size: u64,
+
+    // ETag or Last-Modified of the file, a changed file is downloaded again
+    pub validator: Option<String>,
+    pub chunks: Vec<Chunk>,
+}
+
+impl GetState {
+    // Split the file into at most `connections` chunks of at least min_chunk bytes
+    pub fn build(
+        url: &str,
+        size: u64,
+        validator: Option<String>,
+        connections: usize,
+        min_chunk: u64,
+    ) -> Self {
+        let count = (size / min_chunk.max(1)).clamp(1, connections.max(1) as u64);
+        let chunk_size = size.div_ceil(count);
+        let chunks = (0..count)
+            .map(|i| Chunk {
+                start: i * chunk_size,
+                end: ((i + 1) * chunk_size).min(size),
+                done: 0,
+            })
+            .filter(|c| c.start < c.end)
+            .collect();
+        Self {
+            url: url.to_string(),
+            size,
+            validator,
+            chunks,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| io_error("Cannot read download state", path, e))?;
+        toml::from_str(&content)
+            .map_err(|e| Error::Parse(format!("Invalid download state {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self)
+            .map_err(|e| Error::Parse(format!("Cannot write download state: {}", e)))?;
+        let dir = path.parent().and_then(|p| p.to_str()).unwrap_or_default();
+        let dir = if dir.is_empty() { "." } else { dir };
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .expect("State file has a name");
+        overwrite_data_file(content.as_bytes(), dir, name)
+    }
+
+    // Same file as the one of this state
+    pub fn matches(&self, size: u64, validator: &Option<String>) -> bool {
+        self.size == size && (self.validator.is_none() || &self.validator == validator)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.chunks.iter().map(|c| c.done).sum()
+    }
+}
@@ -0,0 +1,212 @@
+#[cfg(test)]
+mod tests {
+    use crate::state::GetState;
+    use crate::{
+        content_disposition_name, download, file_name, parse_checksum, Checksum, GetConfig,
+        MIN_CHUNK_SIZE,
+    };
+    use reqwest::header::{HeaderMap, HeaderValue, CONTENT_DISPOSITION};
+    use saidl_helper::http::HttpClient;
+    use saidl_helper::retry::RetryPolicy;
+    use saidl_helper::test_util::{http_response, test_dir, TestServer};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Serve the data with Range support, the number of sent body bytes is counted
+    async fn serve_file(data: Arc<Vec<u8>>) -> (String, Arc<AtomicUsize>) {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let server = TestServer::serve(move |request| {
+            let request = request.to_ascii_lowercase();
+            let (start, end) = request
+                .lines()
+                .find_map(|l| l.strip_prefix("range: bytes="))
+                .and_then(|r| r.trim().split_once('-'))
+                .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()))
+                .unwrap();
+            let body = &data[start..=end];
+            counter.fetch_add(body.len(), Ordering::SeqCst);
+            let headers = format!(
+                "Content-Range: bytes {}-{}/{}\r\nETag: \"v1\"\r\n",
+                start,
+                end,
+                data.len()
+            );
+            http_response("206 Partial Content", &headers, body)
+        })
+        .await;
+        (format!("{}/files/video%20one.mp4", server.url), sent)
+    }
+
+    // Partial content of unknown size to a range request, 416 when the file is empty
+    async fn serve_unknown_size(data: &'static [u8]) -> String {
+        let server = TestServer::serve(move |request| {
+            match (
+                request.to_ascii_lowercase().contains("range: bytes="),
+                data.len(),
+            ) {
+                (true, 0) => http_response(
+                    "416 Range Not Satisfiable",
+                    "Content-Range: bytes */0\r\n",
+                    b"",
+                ),
+                (true, _) => http_response(
+                    "206 Partial Content",
+                    "Content-Range: bytes 0-0/*\r\n",
+                    &data[..1],
+                ),
+                (false, _) => http_response("200 OK", "", data),
+            }
+        })
+        .await;
+        format!("{}/notes.txt", server.url)
+    }
+
+    fn test_data() -> Arc<Vec<u8>> {
+        let size = 3 * MIN_CHUNK_SIZE as usize + 12345;
+        Arc::new((0..size).map(|i| (i * 31 % 251) as u8).collect())
+    }
+
+    fn config<'a>(
+        client: &'a HttpClient,
+        output: &str,
+        checksum: Option<Checksum>,
+    ) -> GetConfig<'a> {
+        GetConfig {
+            client,
+            output: Some(output.to_string()),
+            connections: 4,
+            delay: None,
+            retry: RetryPolicy::default(),
+            checksum,
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_download_work() {
+        let data = test_data();
+        let (url, _) = serve_file(data.clone()).await;
+        let dir = test_dir("get-parallel");
+        let output = dir.join("video.mp4").to_str().unwrap().to_string();
+        let digest = hex::encode(Sha256::digest(&data[..]));
+        let checksum = parse_checksum(&format!("sha256:{}", digest)).unwrap();
+
+        let client = HttpClient::build(&None, false, None, None).unwrap();
+        let path = download(&url, config(&client, &output, Some(checksum)))
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), *data);
+        assert!(!dir.join("video.mp4.part").exists());
+        assert!(!dir.join("video.mp4.part.toml").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_download_work() {
+        let data = test_data();
+        let (url, sent) = serve_file(data.clone()).await;
+        let dir = test_dir("get-resume");
+        let output = dir.join("video.mp4").to_str().unwrap().to_string();
+
+        // First chunk was downloaded before the interruption
+        let mut state = GetState::build(&url, data.len() as u64, None, 4, MIN_CHUNK_SIZE);
+        assert_eq!(state.chunks.len(), 3);
+        let first = state.chunks[0].clone();
+        state.chunks[0].done = first.end - first.start;
+        state.validator = Some("\"v1\"".to_string());
+        state.save(&dir.join("video.mp4.part.toml")).unwrap();
+        let mut part = data[..first.end as usize].to_vec();
+        part.resize(data.len(), 0);
+        fs::write(dir.join("video.mp4.part"), part).unwrap();
+
+        let client = HttpClient::build(&None, false, None, None).unwrap();
+        let path = download(&url, config(&client, &output, None))
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), *data);
+        // Probe byte and the other chunks
+        assert_eq!(
+            sent.load(Ordering::SeqCst),
+            1 + data.len() - first.end as usize
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_fail() {
+        let data = test_data();
+        let (url, _) = serve_file(data).await;
+        let dir = test_dir("get-checksum");
+        let output = dir.join("video.mp4").to_str().unwrap().to_string();
+        let checksum = Checksum::Sha256("0".repeat(64));
+
+        let client = HttpClient::build(&None, false, None, None).unwrap();
+        let error = download(&url, config(&client, &output, Some(checksum)))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch"));
+        assert!(!dir.join("video.mp4").exists());
+        assert!(!dir.join("video.mp4.part").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_size_download_work() {
+        let dir = test_dir("get-unknown");
+        let client = HttpClient::build(&None, false, None, None).unwrap();
+        for data in [&b"hello world"[..], &b""[..]] {
+            let url = serve_unknown_size(data).await;
+            let output = dir.join("notes.txt").to_str().unwrap().to_string();
+            let path = download(&url, config(&client, &output, None))
+                .await
+                .unwrap();
+            assert_eq!(fs::read(&path).unwrap(), data);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_name_work() {
+        assert_eq!(
+            content_disposition_name("attachment; filename=\"report.pdf\"").as_deref(),
+            Some("report.pdf")
+        );
+        assert_eq!(
+            content_disposition_name(
+                "attachment; filename=\"naive.txt\"; filename*=UTF-8''na%C3%AFve.txt"
+            )
+            .as_deref(),
+            Some("naïve.txt")
+        );
+        assert_eq!(
+            content_disposition_name("attachment; filename*=broken; filename=\"report.pdf\"")
+                .as_deref(),
+            Some("report.pdf")
+        );
+        assert_eq!(content_disposition_name("inline"), None);
+
+        let mut headers = HeaderMap::new();
+        let url = "https://example.com/files/video%20one.mp4?token=1";
+        assert_eq!(file_name(&headers, url), "video one.mp4");
+        assert_eq!(file_name(&headers, "https://example.com/"), "download");
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"../../etc/passwd\""),
+        );
+        assert_eq!(file_name(&headers, url), "passwd");
+    }
+
+    #[test]
+    fn parse_checksum_work() {
+        let digest = "a".repeat(64);
+        assert_eq!(
+            parse_checksum(&format!("SHA256:{}", digest.to_uppercase())),
+            Ok(Checksum::Sha256(digest))
+        );
+        assert!(parse_checksum(&format!("sha512:{}", "b".repeat(128))).is_ok());
+        assert!(parse_checksum("md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
+        assert!(parse_checksum("sha256:xyz").is_err());
+    }
+}
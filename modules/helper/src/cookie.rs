@@ -0,0 +1,158 @@
+use crate::error::{Error, Result};
+use crate::file::io_error;
+use cookie_store::{CookieDomain, CookieExpiration, CookieStore, RawCookie};
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+// Cookies of the client, updated by Set-Cookie of every response during a run
+#[derive(Default)]
+pub struct CookieJar {
+    store: Mutex<CookieStore>,
+}
+
+impl CookieJar {
+    // Load a Netscape cookies.txt, as exported by browsers or written by curl
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).map_err(|e| io_error("Cannot read cookie file", path, e))?;
+        Self::parse_netscape(&content)
+            .map_err(|e| Error::Parse(format!("Invalid cookie file {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_netscape())
+            .map_err(|e| io_error("Cannot save cookie file", path, e))
+    }
+
+    // Tab separated lines of domain, include subdomains, path, secure, expiry, name and value
+    pub fn parse_netscape(content: &str) -> std::result::Result<Self, String> {
+        let mut store = CookieStore::default();
+        for (number, line) in content.lines().enumerate() {
+            let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+                return Err(format!(
+                    "line {} must have 7 tab separated fields",
+                    number + 1
+                ));
+            };
+            let expires: i64 = expires
+                .parse()
+                .map_err(|_| format!("line {} has invalid expiry {}", number + 1, expires))?;
+            let host = domain.trim_start_matches('.');
+            let secure = secure.eq_ignore_ascii_case("TRUE");
+            let mut cookie = RawCookie::build(name.to_string(), value.to_string())
+                .path(path.to_string())
+                .secure(secure)
+                .http_only(http_only);
+            if subdomains.eq_ignore_ascii_case("TRUE") {
+                cookie = cookie.domain(host.to_string());
+            }
+            // Expiry 0 is a session cookie
+            if expires > 0 {
+                let expires = OffsetDateTime::from_unix_timestamp(expires)
+                    .map_err(|_| format!("line {} has invalid expiry {}", number + 1, expires))?;
+                cookie = cookie.expires(expires);
+            }
+            let scheme = if secure { "https" } else { "http" };
+            let url = Url::parse(&format!("{}://{}{}", scheme, host, path))
+                .map_err(|_| format!("line {} has invalid domain {}", number + 1, domain))?;
+            // Expired cookies are not kept
+            let _ = store.insert_raw(&cookie.finish(), &url);
+        }
+        Ok(Self {
+            store: Mutex::new(store),
+        })
+    }
+
+    // Cookies sent to the url, kept for every path of its host
+    pub fn add(&self, url: &str, cookies: &[(String, String)]) -> Result<()> {
+        let url =
+            Url::parse(url).map_err(|_| Error::Parse(format!("Invalid cookie url: {}", url)))?;
+        let mut store = self.store.lock().expect("Cookie jar lock is poisoned");
+        for (name, value) in cookies {
+            let cookie = RawCookie::build(name.clone(), value.clone())
+                .path("/")
+                .finish();
+            store
+                .insert_raw(&cookie, &url)
+                .map_err(|e| Error::Parse(format!("Invalid cookie {}: {}", name, e)))?;
+        }
+        Ok(())
+    }
+
+    // Unexpired cookies in Netscape format, session cookies are written with expiry 0
+    pub fn to_netscape(&self) -> String {
+        let store = self.store.lock().expect("Cookie jar lock is poisoned");
+        let mut content = format!("{}\n", NETSCAPE_HEADER);
+        for cookie in store.iter_unexpired() {
+            let (domain, subdomains) = match &cookie.domain {
+                CookieDomain::Suffix(domain) => (format!(".{}", domain), "TRUE"),
+                CookieDomain::HostOnly(domain) => (domain.clone(), "FALSE"),
+                _ => continue,
+            };
+            let expires = match cookie.expires {
+                CookieExpiration::AtUtc(time) => time.unix_timestamp(),
+                CookieExpiration::SessionEnd => 0,
+            };
+            let prefix = if cookie.http_only().unwrap_or(false) {
+                HTTP_ONLY_PREFIX
+            } else {
+                ""
+            };
+            let secure = if cookie.secure().unwrap_or(false) {
+                "TRUE"
+            } else {
+                "FALSE"
+            };
+            content.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                prefix,
+                domain,
+                subdomains,
+                &*cookie.path,
+                secure,
+                expires,
+                cookie.name(),
+                cookie.value()
+            ));
+        }
+        content
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| RawCookie::parse(v.to_string()).ok());
+        let mut store = self.store.lock().expect("Cookie jar lock is poisoned");
+        store.store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let store = self.store.lock().expect("Cookie jar lock is poisoned");
+        let value = store
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if value.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
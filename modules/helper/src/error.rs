@@ -0,0 +1,75 @@
+use crate::retry::is_retryable_status;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Failure of a download, the cli prints the message and exits with its code
+#[derive(Debug)]
+pub enum Error {
+    // Connection, timeout or broken response body
+    Network(String),
+    HttpStatus { code: u16, url: String },
+    // Invalid playlist, config, key or media data
+    Parse(String),
+    // Invalid css selector or no element is matched
+    Selector(String),
+    Io(String),
+    Ffmpeg(String),
+}
+
+impl Error {
+    // Exit code 2 is left for invalid command line arguments
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Network(_) => 3,
+            Error::HttpStatus { .. } => 4,
+            Error::Parse(_) => 5,
+            Error::Selector(_) => 6,
+            Error::Io(_) => 7,
+            Error::Ffmpeg(_) => 8,
+        }
+    }
+
+    // Whether sending the request again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network(_) => true,
+            Error::HttpStatus { code, .. } => is_retryable_status(*code),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(message) => write!(f, "Network error: {}", message),
+            Error::HttpStatus { code, url } => write!(f, "HTTP status {} for {}", code, url),
+            Error::Parse(message) => write!(f, "Parse error: {}", message),
+            Error::Selector(message) => write!(f, "Selector error: {}", message),
+            Error::Io(message) => write!(f, "IO error: {}", message),
+            Error::Ffmpeg(message) => write!(f, "ffmpeg error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        match (e.status(), e.url()) {
+            (Some(status), Some(url)) => Error::HttpStatus {
+                code: status.as_u16(),
+                url: url.to_string(),
+            },
+            _ => Error::Network(e.to_string()),
+        }
+    }
+}
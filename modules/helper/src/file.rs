@@ -1,16 +1,17 @@
-use crate::get_format_msg;
+use crate::error::{Error, Result};
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(windows)]
-const LINE_ENDING: &'static str = "\r\n";
+const LINE_ENDING: &str = "\r\n";
 #[cfg(not(windows))]
-const LINE_ENDING: &'static str = "\n";
+const LINE_ENDING: &str = "\n";
 
-pub fn write_data_file(data: &[u8], directory_name: &str, file_name: &str) {
+pub fn write_data_file(data: &[u8], directory_name: &str, file_name: &str) -> Result<()> {
     let mut full_file_path = String::new();
     full_file_path.push_str(directory_name);
     full_file_path.push_str(file_name);
@@ -18,40 +19,70 @@ pub fn write_data_file(data: &[u8], directory_name: &str, file_name: &str) {
     if path.exists() {
         println!("File already exists {}", full_file_path);
     } else {
-        let mut file = File::create(path).expect(&get_format_msg("Cannot create file", file_name));
+        let mut file = File::create(path).map_err(|e| io_error("Cannot create file", path, e))?;
         file.write_all(data)
-            .expect(&get_format_msg("Cannot write file", file_name));
+            .map_err(|e| io_error("Cannot write file", path, e))?;
     }
+    Ok(())
 }
 
-pub fn create_output_folder() -> String {
+// Replace the file content, a temporary file is renamed so readers never see partial data
+pub fn overwrite_data_file(data: &[u8], directory_name: &str, file_name: &str) -> Result<()> {
+    let path = Path::new(directory_name).join(file_name);
+    let temp_path = Path::new(directory_name).join(format!("{}.part", file_name));
+    let mut file =
+        File::create(&temp_path).map_err(|e| io_error("Cannot create file", &temp_path, e))?;
+    file.write_all(data)
+        .map_err(|e| io_error("Cannot write file", &temp_path, e))?;
+    fs::rename(&temp_path, &path).map_err(|e| io_error("Cannot write file", &path, e))
+}
+
+// Join files of a directory in order into the output file
+pub fn concat_files(directory_name: &str, file_names: &[String], output_path: &Path) -> Result<()> {
+    let mut output =
+        File::create(output_path).map_err(|e| io_error("Cannot create file", output_path, e))?;
+    for file_name in file_names {
+        let input_path = Path::new(directory_name).join(file_name);
+        let mut input =
+            File::open(&input_path).map_err(|e| io_error("Cannot open file", &input_path, e))?;
+        io::copy(&mut input, &mut output)
+            .map_err(|e| io_error("Cannot write file", output_path, e))?;
+    }
+    Ok(())
+}
+
+pub fn create_output_folder() -> Result<String> {
     // Create sub-folder in current folder by timestamp
     let start = SystemTime::now();
     let since_epoch = start
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    let timestamp = since_epoch.as_millis().to_string();
-    let mut path: String = String::from("sai-output");
-    path.push_str(&timestamp);
-    path.push_str("/");
-    fs::create_dir(&path).expect(&get_format_msg("Cannot create folder", &path));
-    return path;
+    let mut timestamp = since_epoch.as_millis();
+    loop {
+        let path = format!("sai-output{}/", timestamp);
+        match fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            // Another download started in the same millisecond
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => timestamp += 1,
+            Err(e) => return Err(io_error("Cannot create folder", Path::new(&path), e)),
+        }
+    }
 }
 
-pub fn remove_download_folder(dir: &str) {
-    fs::remove_dir_all(dir).expect(&get_format_msg("Cannot remove folder", dir));
+pub fn remove_download_folder(dir: &str) -> Result<()> {
+    fs::remove_dir_all(dir).map_err(|e| io_error("Cannot remove folder", Path::new(dir), e))
 }
 
-pub fn get_raw_file_content(path: PathBuf) -> String {
-    fs::read_to_string(path).unwrap()
+pub fn get_raw_file_content(path: PathBuf) -> Result<String> {
+    fs::read_to_string(&path).map_err(|e| io_error("Cannot read file", &path, e))
 }
 
-pub fn get_lines(path: PathBuf) -> Vec<String> {
-    let content = get_raw_file_content(path);
+pub fn get_lines(path: PathBuf) -> Result<Vec<String>> {
+    let content = get_raw_file_content(path)?;
     let lines = content.split(LINE_ENDING);
 
     // Remove empty lines
-    lines
+    Ok(lines
         .filter_map(|mut line| {
             line = line.trim();
             if line.is_empty() {
@@ -60,5 +91,9 @@ pub fn get_lines(path: PathBuf) -> Vec<String> {
                 Some(line.to_string())
             }
         })
-        .collect()
+        .collect())
+}
+
+pub fn io_error(message: &str, path: &Path, e: io::Error) -> Error {
+    Error::Io(format!("{} {}: {}", message, path.display(), e))
 }
@@ -0,0 +1,333 @@
+use crate::error::{Error, Result};
+use crate::file::io_error;
+use http::{header::HeaderName, HeaderValue};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Headers set by the client itself. A copied Accept-Encoding would turn off the
+// automatic decompression and conditional headers would only get 304 responses.
+const IGNORED_HEADERS: [&str; 8] = [
+    "host",
+    "content-length",
+    "connection",
+    "accept-encoding",
+    "range",
+    "if-none-match",
+    "if-modified-since",
+    "cookie",
+];
+
+// Flags of curl that take a value, other flags are switches
+const CURL_VALUE_FLAGS: [&str; 27] = [
+    "-H",
+    "--header",
+    "-X",
+    "--request",
+    "-b",
+    "--cookie",
+    "-A",
+    "--user-agent",
+    "-e",
+    "--referer",
+    "--url",
+    "-d",
+    "--data",
+    "--data-raw",
+    "--data-binary",
+    "--data-ascii",
+    "--data-urlencode",
+    "-F",
+    "--form",
+    "-u",
+    "--user",
+    "-o",
+    "--output",
+    "-x",
+    "--proxy",
+    "-m",
+    "--max-time",
+];
+
+// Request copied from the browser devtools
+#[derive(Debug)]
+pub struct ImportedRequest {
+    pub url: String,
+    pub method: String,
+    pub headers: HeaderMap,
+    pub cookies: Vec<(String, String)>,
+
+    // Content type of the response, only known for HAR entries
+    pub mime_type: Option<String>,
+}
+
+impl ImportedRequest {
+    fn build(url: String, method: String) -> Self {
+        Self {
+            url,
+            method,
+            headers: HeaderMap::new(),
+            cookies: Vec::new(),
+            mime_type: None,
+        }
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) -> std::result::Result<(), String> {
+        let name = name.trim();
+        let value = value.trim();
+        // Pseudo-headers of HTTP/2 are part of the url
+        if name.starts_with(':') {
+            return Ok(());
+        }
+        if name.eq_ignore_ascii_case("cookie") {
+            self.cookies.extend(parse_cookie_header(value));
+        }
+        if IGNORED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Ok(());
+        }
+        let header_name =
+            HeaderName::from_str(name).map_err(|_| format!("invalid header name {}", name))?;
+        let header_value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value of header {}", name))?;
+        self.headers.append(header_name, header_value);
+        Ok(())
+    }
+}
+
+pub fn load_curl(path: &Path) -> Result<ImportedRequest> {
+    let content =
+        fs::read_to_string(path).map_err(|e| io_error("Cannot read curl file", path, e))?;
+    parse_curl(&content)
+        .map_err(|e| Error::Parse(format!("Invalid curl command {}: {}", path.display(), e)))
+}
+
+pub fn load_har(path: &Path) -> Result<Vec<ImportedRequest>> {
+    let content =
+        fs::read_to_string(path).map_err(|e| io_error("Cannot read HAR file", path, e))?;
+    parse_har(&content)
+        .map_err(|e| Error::Parse(format!("Invalid HAR file {}: {}", path.display(), e)))
+}
+
+// Command of "Copy as cURL" in the bash format
+pub fn parse_curl(command: &str) -> std::result::Result<ImportedRequest, String> {
+    let words = split_shell_words(command)?;
+    let mut words = words.into_iter();
+    match words.next() {
+        Some(word) if word == "curl" || word.ends_with("/curl") => {}
+        _ => return Err("command must start with curl".to_string()),
+    }
+    let mut url = None;
+    let mut method = None;
+    let mut has_data = false;
+    let mut headers = Vec::new();
+    let mut cookies = Vec::new();
+    while let Some(word) = words.next() {
+        if !word.starts_with('-') {
+            url.get_or_insert(word);
+            continue;
+        }
+        if !CURL_VALUE_FLAGS.contains(&word.as_str()) {
+            continue;
+        }
+        let value = words
+            .next()
+            .ok_or_else(|| format!("flag {} has no value", word))?;
+        match word.as_str() {
+            "-H" | "--header" => match value.split_once(':') {
+                Some((name, value)) => headers.push((name.to_string(), value.to_string())),
+                None => return Err(format!("invalid header {}", value)),
+            },
+            "-X" | "--request" => method = Some(value.to_ascii_uppercase()),
+            "-b" | "--cookie" => cookies.extend(parse_cookie_header(&value)),
+            "-A" | "--user-agent" => headers.push(("User-Agent".to_string(), value)),
+            "-e" | "--referer" => headers.push(("Referer".to_string(), value)),
+            "--url" => url = Some(value),
+            "-d" | "--data" | "--data-raw" | "--data-binary" | "--data-ascii"
+            | "--data-urlencode" | "-F" | "--form" => has_data = true,
+            _ => {}
+        }
+    }
+    let url = url.ok_or("no url in the command")?;
+    let method = method.unwrap_or_else(|| if has_data { "POST" } else { "GET" }.to_string());
+    let mut request = ImportedRequest::build(url, method);
+    for (name, value) in headers {
+        request.add_header(&name, &value)?;
+    }
+    request.cookies.extend(cookies);
+    Ok(request)
+}
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+struct HarEntry {
+    request: HarRequest,
+    response: Option<HarResponse>,
+}
+
+#[derive(Deserialize)]
+struct HarRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<HarPair>,
+    #[serde(default)]
+    cookies: Vec<HarPair>,
+}
+
+#[derive(Deserialize)]
+struct HarResponse {
+    content: Option<HarContent>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarContent {
+    mime_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HarPair {
+    name: String,
+    value: String,
+}
+
+// Requests of a HAR capture in the recorded order
+pub fn parse_har(content: &str) -> std::result::Result<Vec<ImportedRequest>, String> {
+    let har: Har = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let mut requests = Vec::new();
+    for entry in har.log.entries {
+        let HarRequest {
+            method,
+            url,
+            headers,
+            cookies,
+        } = entry.request;
+        let mut request = ImportedRequest::build(url, method);
+        for header in headers {
+            request.add_header(&header.name, &header.value)?;
+        }
+        // Cookies are listed both in the Cookie header and the cookies field
+        if !cookies.is_empty() {
+            request.cookies = cookies.into_iter().map(|c| (c.name, c.value)).collect();
+        }
+        request.mime_type = entry
+            .response
+            .and_then(|r| r.content)
+            .and_then(|c| c.mime_type)
+            .filter(|m| !m.is_empty());
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+fn parse_cookie_header(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+// Split a bash command into words, with '...', "...", $'...' quotes and line continuations
+fn split_shell_words(command: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(w) = word.take() {
+                    words.push(w);
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') | None => {}
+                Some('\r') if chars.peek() == Some(&'\n') => {
+                    chars.next();
+                }
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+            },
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err("unclosed single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => w.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                            None => return Err("unclosed double quote".to_string()),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err("unclosed double quote".to_string()),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') => w.push(ansi_c_escape(&mut chars)?),
+                        Some(c) => w.push(c),
+                        None => return Err("unclosed $' quote".to_string()),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(w) = word {
+        words.push(w);
+    }
+    Ok(words)
+}
+
+// Escape sequence of $'...' after the backslash
+fn ansi_c_escape(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> std::result::Result<char, String> {
+    let length = match chars.next() {
+        Some('n') => return Ok('\n'),
+        Some('r') => return Ok('\r'),
+        Some('t') => return Ok('\t'),
+        Some('x') => 2,
+        Some('u') => 4,
+        Some('U') => 8,
+        Some(c) => return Ok(c),
+        None => return Err("unclosed $' quote".to_string()),
+    };
+    let digits: String = (0..length)
+        .map_while(|_| chars.next_if(|c| c.is_ascii_hexdigit()))
+        .collect();
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("invalid escape \\{}", digits))
+}
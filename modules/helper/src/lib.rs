@@ -1,29 +1,34 @@
 extern crate core;
 
+pub mod cookie;
+pub mod error;
 pub mod file;
 pub mod http;
+pub mod import;
+pub mod rate;
+pub mod retry;
+#[cfg(test)]
+mod test;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 use std::fmt::Display;
+use std::io;
 use std::process::Command;
 
 pub fn get_format_msg(base_msg: &str, format_obj: impl Display) -> String {
-    return format!("\n{}\n{}", base_msg, format_obj);
+    format!("\n{}\n{}", base_msg, format_obj)
 }
 
-pub fn run_os_command(command: &str) {
+// Whether the command exits successfully
+pub fn run_os_command(command: &str) -> io::Result<bool> {
     let output = if cfg!(target_os = "windows") {
-        Command::new("powershell")
-            .args(["/C", command])
-            .output()
-            .expect("failed to execute process")
+        Command::new("powershell").args(["/C", command]).output()?
     } else {
-        Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()
-            .expect("failed to execute process")
+        Command::new("sh").arg("-c").arg(command).output()?
     };
     println!("status: {}", output.status);
     println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
     println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
+    Ok(output.status.success())
 }
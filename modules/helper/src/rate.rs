@@ -0,0 +1,79 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// Requests allowed per period, such as 5/s, 30/m or 0.5/s
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub requests: f64,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    // Time between two requests to the same host
+    pub fn interval(&self) -> Duration {
+        self.period.div_f64(self.requests)
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_rate(&value)
+    }
+}
+
+// Rate in N/s, N/m or N/h format
+pub fn parse_rate(value: &str) -> Result<RateLimit, String> {
+    let invalid = || format!("Invalid rate: {}, expected such as 5/s or 30/m", value);
+    let (requests, unit) = value.split_once('/').ok_or_else(invalid)?;
+    let requests: f64 = requests.trim().parse().map_err(|_| invalid())?;
+    if !requests.is_finite() || requests <= 0.0 {
+        return Err(invalid());
+    }
+    let period = match unit.trim() {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(3600),
+        _ => return Err(invalid()),
+    };
+    Ok(RateLimit { requests, period })
+}
+
+// Token bucket of one token per host, refilled once per interval of the rate.
+// A request takes the next free slot of its host before waiting, so concurrent
+// requests are spaced evenly and never exceed the rate in any window.
+#[derive(Clone)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_slots: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn build(rate: RateLimit) -> Self {
+        Self {
+            interval: rate.interval(),
+            next_slots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Wait until a request to the host is allowed
+    pub async fn acquire(&self, host: &str) {
+        let now = Instant::now();
+        let slot = {
+            let mut next_slots = self
+                .next_slots
+                .lock()
+                .expect("Rate limiter lock is poisoned");
+            let next = next_slots.entry(host.to_string()).or_insert(now);
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+        time::sleep_until(slot).await;
+    }
+}
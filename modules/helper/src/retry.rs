@@ -0,0 +1,91 @@
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_BASE_DELAY: u64 = 1;
+const DEFAULT_MAX_DELAY: u64 = 60;
+
+// How failed requests are retried. The wait before each retry doubles from base_delay
+// up to max_delay with random jitter, unless the server asks for a wait with Retry-After.
+// The wait of Retry-After is capped at max_delay too.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(from = "RetryField")]
+pub struct RetryPolicy {
+    pub times: u8,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::build(0, None, None)
+    }
+}
+
+impl RetryPolicy {
+    // Delays are in seconds
+    pub fn build(times: u8, base_delay: Option<u64>, max_delay: Option<u64>) -> Self {
+        Self {
+            times,
+            base_delay: Duration::from_secs(base_delay.unwrap_or(DEFAULT_BASE_DELAY)),
+            max_delay: Duration::from_secs(max_delay.unwrap_or(DEFAULT_MAX_DELAY)),
+        }
+    }
+
+    // Wait before the retry after `attempt` failed retries, between half and all of the
+    // capped exponential delay so that parallel downloads do not retry at the same time
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+    }
+
+    // Retry-After of the server wins over the backoff, up to max_delay
+    pub fn wait(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(wait) => wait.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+// Retry field of a config, either the retry times or a table of the policy
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RetryField {
+    Times(u8),
+    Policy {
+        times: u8,
+        base_delay: Option<u64>,
+        max_delay: Option<u64>,
+    },
+}
+
+impl From<RetryField> for RetryPolicy {
+    fn from(field: RetryField) -> Self {
+        match field {
+            RetryField::Times(times) => Self::build(times, None, None),
+            RetryField::Policy {
+                times,
+                base_delay,
+                max_delay,
+            } => Self::build(times, base_delay, max_delay),
+        }
+    }
+}
+
+// Timeouts, rate limits and server side failures may succeed later, other statuses will not
+pub fn is_retryable_status(code: u16) -> bool {
+    matches!(code, 408 | 425 | 429 | 500 | 502 | 503 | 504)
+}
+
+// Retry-After is either a number of seconds or an http date
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
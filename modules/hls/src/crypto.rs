@@ -0,0 +1,73 @@
+use crate::playlist::KeyMethod;
+use crate::sample_aes::{decrypt_sample_aes, CbcsPatterns};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use saidl_helper::error::{Error, Result};
+use saidl_helper::file::io_error;
+use std::fs;
+use std::path::Path;
+
+pub const KEY_SIZE: usize = 16;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+// Key and IV used to decrypt a single segment
+#[derive(Clone)]
+pub struct Decryption {
+    pub method: KeyMethod,
+    pub key: [u8; KEY_SIZE],
+    pub iv: [u8; KEY_SIZE],
+
+    // Pattern of each track of SAMPLE-AES fMP4 segments
+    pub patterns: CbcsPatterns,
+}
+
+impl Decryption {
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.method {
+            KeyMethod::Aes128 => decrypt_aes128(data, &self.key, &self.iv),
+            KeyMethod::SampleAes => decrypt_sample_aes(data, &self.key, &self.iv, &self.patterns),
+        }
+    }
+}
+
+pub fn decrypt_aes128(data: &[u8], key: &[u8; KEY_SIZE], iv: &[u8; KEY_SIZE]) -> Result<Vec<u8>> {
+    Aes128CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| Error::Parse("Failed to decrypt segment, wrong key or iv".to_string()))
+}
+
+// Default IV is the media sequence number as a big-endian 128 bit integer
+pub fn sequence_iv(sequence: u64) -> [u8; KEY_SIZE] {
+    (sequence as u128).to_be_bytes()
+}
+
+// Parse hex string with optional 0x prefix, such as IV attribute of EXT-X-KEY
+pub fn parse_hex_key(value: &str) -> std::result::Result<[u8; KEY_SIZE], String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    let mut key = [0u8; KEY_SIZE];
+    hex::decode_to_slice(digits, &mut key)
+        .map_err(|_| format!("Invalid 128 bit hex value: {}", value))?;
+    Ok(key)
+}
+
+// Key file can be 16 raw bytes or a hex string
+pub fn read_key_file(path: &Path) -> Result<[u8; KEY_SIZE]> {
+    let data = fs::read(path).map_err(|e| io_error("Cannot read key file", path, e))?;
+    if data.len() == KEY_SIZE {
+        return Ok(data.try_into().expect("Length is checked"));
+    }
+    parse_hex_key(String::from_utf8_lossy(&data).trim()).map_err(Error::Parse)
+}
+
+pub fn to_key(data: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    data.try_into().map_err(|_| {
+        Error::Parse(format!(
+            "Key must be {} bytes, got {} bytes",
+            KEY_SIZE,
+            data.len()
+        ))
+    })
+}
@@ -1,160 +1,652 @@
 extern crate core;
 
+pub mod crypto;
+pub mod live;
+pub mod manifest;
+pub mod playlist;
+pub mod pool;
+mod remux;
+mod sample_aes;
 #[cfg(test)]
 mod test;
+mod ts;
 
+use crate::crypto::{sequence_iv, to_key, Decryption, KEY_SIZE};
+use crate::manifest::{checksum, JobManifest, SegmentJob, SegmentStatus};
+use crate::playlist::{
+    is_master_playlist, is_playlist, parse_link_list, parse_master_playlist, parse_media_playlist,
+    ByteRange, InitSection, KeyMethod, MediaPlaylist, MediaSegment, SegmentKey, VariantFilter,
+};
+use crate::pool::{run_pool, OrderedProgress};
+use crate::remux::remux_ts_to_mp4;
+use crate::sample_aes::{parse_cbcs_patterns, CbcsPatterns};
 use bytes::Bytes;
-use reqwest::header::HeaderMap;
-use saidl_helper::file::{create_output_folder, remove_download_folder, write_data_file};
-use saidl_helper::{get_format_msg, http::send_wrapped_request, run_os_command};
-use std::fmt;
-use tokio;
+use reqwest::{StatusCode, Url};
+use saidl_helper::file::{
+    concat_files, create_output_folder, get_raw_file_content, overwrite_data_file,
+    remove_download_folder, write_data_file,
+};
+use saidl_helper::{
+    error::{Error, Result},
+    http::{with_range, HttpClient},
+    retry::RetryPolicy,
+    run_os_command,
+};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Completed segments are written to the job manifest at most once per interval
+const MANIFEST_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct HLSConfig<'a> {
+    pub png: bool,
+    // Concurrent segment downloads in total and per host
+    pub jobs: usize,
+    pub host_jobs: Option<usize>,
+    pub keep: bool,
+    pub client: &'a HttpClient,
+    pub output: Option<String>,
+    pub delay: Option<u64>,
+    pub retry: RetryPolicy,
+
+    // Variant selection when input is a master playlist
+    pub variant: VariantFilter,
+
+    // Use this key instead of fetching key uri from the playlist
+    pub key: Option<[u8; KEY_SIZE]>,
+
+    // Join segments with ffmpeg instead of the built-in remuxer
+    pub ffmpeg: bool,
+    // Remux MPEG-TS segments into MP4 instead of writing a .ts file
+    pub mp4: bool,
+}
 
 pub async fn get_response_bytes(
     url: &str,
-    headers: &Option<HeaderMap>,
-    h2: bool,
+    client: &HttpClient,
     delay: Option<u64>,
-    retry: Option<u8>,
-) -> Result<Bytes, fmt::Error> {
-    let response = send_wrapped_request(url, headers, h2, delay, retry).await?;
-    let data = response
-        .bytes()
-        .await
-        .expect(&get_format_msg("Unpack data failed: {}", url));
-    return Ok(data);
+    retry: RetryPolicy,
+) -> Result<Bytes> {
+    let response = client
+        .send_wrapped_request(url, &None, delay, retry)
+        .await?;
+    Ok(response.bytes().await?)
+}
+
+// Fetch a sub-range when range is set, the server may ignore it and send the whole file
+pub async fn get_response_range_bytes(
+    url: &str,
+    range: Option<&ByteRange>,
+    client: &HttpClient,
+    delay: Option<u64>,
+    retry: RetryPolicy,
+) -> Result<Bytes> {
+    let range = match range {
+        Some(r) => r,
+        None => return get_response_bytes(url, client, delay, retry).await,
+    };
+    let range_headers = with_range(&None, range.offset, range.length)?;
+    let response = client
+        .send_wrapped_request(url, &range_headers, delay, retry)
+        .await?;
+    let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+    let data = response.bytes().await?;
+    if partial {
+        Ok(data)
+    } else {
+        slice_byte_range(data, range)
+    }
+}
+
+// Input can be a playlist url, a local m3u8 file or a plain text file of segment urls
+pub async fn load_playlist(
+    input: &str,
+    base_url: Option<&str>,
+    config: &HLSConfig<'_>,
+) -> Result<MediaPlaylist> {
+    let (content, base_url) = if input.starts_with("http") {
+        (get_response_text(input, config).await?, Some(input))
+    } else {
+        (get_raw_file_content(PathBuf::from(input))?, base_url)
+    };
+    if !is_playlist(&content) {
+        return Ok(parse_link_list(
+            content.lines().map(|l| l.trim().to_string()),
+        ));
+    }
+    let parsed_base_url = parse_base_url(base_url)?;
+    if !is_master_playlist(&content) {
+        let mut playlist = parse_media_playlist(&content, parsed_base_url.as_ref())?;
+        playlist.url = base_url
+            .filter(|_| input.starts_with("http"))
+            .map(String::from);
+        return Ok(playlist);
+    }
+
+    // Pick a variant then load its media playlist
+    let variants = parse_master_playlist(&content, parsed_base_url.as_ref())?;
+    let variant = match config.variant.select(&variants) {
+        Some(v) => v,
+        None => {
+            let mut message = String::from("No variant matches the selection, available variants:");
+            for v in &variants {
+                message.push_str(&format!("\n{}", v));
+            }
+            return Err(Error::Parse(message));
+        }
+    };
+    println!("Selected variant {}", variant);
+    let media_url = parse_base_url(Some(&variant.uri))?;
+    let content = get_response_text(&variant.uri, config).await?;
+    let mut playlist = parse_media_playlist(&content, media_url.as_ref())?;
+    playlist.url = Some(variant.uri.clone());
+    Ok(playlist)
+}
+
+async fn get_response_text(url: &str, config: &HLSConfig<'_>) -> Result<String> {
+    let response = config
+        .client
+        .send_wrapped_request(url, &None, config.delay, config.retry)
+        .await?;
+    Ok(response.text().await?)
+}
+
+fn parse_base_url(url: Option<&str>) -> Result<Option<Url>> {
+    match url {
+        Some(u) => match Url::parse(u) {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(Error::Parse(format!("Invalid playlist url: {}", u))),
+        },
+        None => Ok(None),
+    }
+}
+
+// Fetch each distinct key once, keys may rotate in the middle of a playlist
+async fn fetch_keys(
+    segments: &[MediaSegment],
+    keys: &mut HashMap<String, [u8; KEY_SIZE]>,
+    config: &HLSConfig<'_>,
+) -> Result<()> {
+    let init_keys = segments
+        .iter()
+        .filter_map(|s| s.init.as_ref().and_then(|i| i.key.as_ref()));
+    for key in segments
+        .iter()
+        .filter_map(|s| s.key.as_ref())
+        .chain(init_keys)
+    {
+        if keys.contains_key(&key.uri) {
+            continue;
+        }
+        if let Some(override_key) = config.key {
+            keys.insert(key.uri.clone(), override_key);
+            continue;
+        }
+        let data = get_response_bytes(&key.uri, config.client, config.delay, config.retry).await?;
+        keys.insert(key.uri.clone(), to_key(&data)?);
+    }
+    Ok(())
+}
+
+fn key_decryption(
+    key: &SegmentKey,
+    sequence: u64,
+    keys: &HashMap<String, [u8; KEY_SIZE]>,
+    patterns: CbcsPatterns,
+) -> Decryption {
+    Decryption {
+        method: key.method.clone(),
+        key: keys[&key.uri],
+        iv: key.iv.unwrap_or_else(|| sequence_iv(sequence)),
+        patterns,
+    }
+}
+
+// Download each distinct initialization section once and keep it with its file name
+// and the cbcs pattern of its tracks
+async fn download_init_sections(
+    segments: &[MediaSegment],
+    keys: &HashMap<String, [u8; KEY_SIZE]>,
+    inits: &mut Vec<(InitSection, String, CbcsPatterns)>,
+    dir: &str,
+    config: &HLSConfig<'_>,
+) -> Result<()> {
+    for segment in segments {
+        let init = match &segment.init {
+            Some(i) => i,
+            None => continue,
+        };
+        if inits.iter().any(|(i, _, _)| i == init) {
+            continue;
+        }
+        let mut data = get_response_range_bytes(
+            &init.uri,
+            init.byte_range.as_ref(),
+            config.client,
+            config.delay,
+            config.retry,
+        )
+        .await?;
+        if config.png {
+            data = strip_png(data);
+        }
+        // Only full segment encryption covers the initialization section
+        if let Some(key) = init.key.as_ref().filter(|k| k.method == KeyMethod::Aes128) {
+            let decryption = key_decryption(key, segment.sequence, keys, CbcsPatterns::new());
+            data = Bytes::from(decryption.decrypt(&data)?);
+        }
+        let patterns = match &segment.key {
+            Some(key) if key.method == KeyMethod::SampleAes => parse_cbcs_patterns(&data)?,
+            _ => CbcsPatterns::new(),
+        };
+        let file_name = format!("init-{}.mp4", inits.len());
+        overwrite_data_file(&data, dir, &file_name)?;
+        inits.push((init.clone(), file_name, patterns));
+    }
+    Ok(())
+}
+
+fn slice_byte_range(data: Bytes, range: &ByteRange) -> Result<Bytes> {
+    let start = range.offset as usize;
+    let end = start.checked_add(range.length as usize);
+    let end = match end {
+        Some(end) if end <= data.len() => end,
+        _ => {
+            return Err(Error::Parse(format!(
+                "Byte range {}@{} is out of {} bytes",
+                range.length,
+                range.offset,
+                data.len()
+            )))
+        }
+    };
+    Ok(data.slice(start..end))
 }
 
 pub fn strip_png(data: Bytes) -> Bytes {
     data.slice(8..)
 }
 
-pub async fn download(
-    input: &Vec<String>,
-    png: bool,
-    h2: bool,
-    multi_thread: bool,
-    keep: bool,
-    headers: &Option<HeaderMap>,
-    output: Option<String>,
-    delay: Option<u64>,
-    retry: Option<u8>,
-) {
-    let list_file = "list.txt";
-    let dir: String = create_output_folder();
-    let mut downloaded_file = String::new();
-
-    let mut fragments = Vec::new();
-    // Download all file
-    for (index, url) in input.iter().enumerate() {
-        let mut file_loc = String::new();
-        let mut file_name = index.to_string();
-        file_name.push_str(".html");
-        file_loc.push_str("file ./");
-        file_loc.push_str(&file_name);
-        file_loc.push_str("\n");
-        downloaded_file.push_str(file_loc.as_str());
-        // download_and_write_fragment(url, headers, png, h2, file_name, &dir).await;
-        fragments.push(HLSFragmentHandler::new(
-            url.to_string(),
-            headers.clone(),
-            png,
-            h2,
-            file_name,
-            dir.clone(),
-            delay,
-            retry,
-        ));
+pub async fn download(playlist: &MediaPlaylist, config: HLSConfig<'_>) -> Result<()> {
+    let mut session = HLSDownload::build(playlist.url.clone())?;
+    session
+        .download_segments(&playlist.segments, &config)
+        .await?;
+    session.finish(config)
+}
+
+// Continue an interrupted download from the job manifest of its working folder
+pub async fn resume(dir: &str, config: HLSConfig<'_>) -> Result<()> {
+    let mut session = HLSDownload::resume(dir)?;
+    let segments: Vec<MediaSegment> = session
+        .manifest
+        .segments
+        .iter()
+        .map(|j| j.segment.clone())
+        .collect();
+    session.download_segments(&segments, &config).await?;
+    session.finish(config)
+}
+
+// Download state kept between batches of segments, such as reloads of a live playlist
+pub struct HLSDownload {
+    dir: String,
+    manifest: JobManifest,
+    keys: HashMap<String, [u8; KEY_SIZE]>,
+    init_files: Vec<(InitSection, String, CbcsPatterns)>,
+    current_init: Option<InitSection>,
+
+    // fMP4 fragments are joined after their initialization section
+    fmp4: bool,
+
+    // Downloaded files in output order
+    files: Vec<String>,
+    next_index: usize,
+    manifest_saved: Instant,
+}
+
+impl HLSDownload {
+    pub fn build(playlist_url: Option<String>) -> Result<Self> {
+        Ok(Self::with_manifest(
+            create_output_folder()?,
+            JobManifest {
+                playlist_url,
+                segments: Vec::new(),
+            },
+        ))
+    }
+
+    // Reuse the working folder and the segment list of a previous download
+    pub fn resume(dir: &str) -> Result<Self> {
+        let mut dir = dir.to_string();
+        if !dir.ends_with('/') {
+            dir.push('/');
+        }
+        let manifest = JobManifest::load(&dir)?;
+        println!("Resume {} segments in {}", manifest.segments.len(), dir);
+        Ok(Self::with_manifest(dir, manifest))
+    }
+
+    fn with_manifest(dir: String, manifest: JobManifest) -> Self {
+        Self {
+            dir,
+            manifest,
+            keys: HashMap::new(),
+            init_files: Vec::new(),
+            current_init: None,
+            fmp4: false,
+            files: Vec::new(),
+            next_index: 0,
+            manifest_saved: Instant::now(),
+        }
     }
-    if multi_thread {
+
+    pub async fn download_segments(
+        &mut self,
+        segments: &[MediaSegment],
+        config: &HLSConfig<'_>,
+    ) -> Result<()> {
+        fetch_keys(segments, &mut self.keys, config).await?;
+        self.fmp4 |= segments.iter().any(|s| s.init.is_some());
+        download_init_sections(
+            segments,
+            &self.keys,
+            &mut self.init_files,
+            &self.dir,
+            config,
+        )
+        .await?;
+        let extension = if self.fmp4 { ".m4s" } else { ".html" };
+
+        let mut fragments = Vec::new();
+        let mut completed = 0;
+        // Download all file
+        for segment in segments {
+            let index = self.next_index;
+            let mut file_name = index.to_string();
+            file_name.push_str(extension);
+            self.next_index += 1;
+            // Initialization section is repeated whenever it changes
+            if self.fmp4 && segment.init != self.current_init {
+                self.current_init = segment.init.clone();
+                if let Some((_, init_file, _)) = self
+                    .init_files
+                    .iter()
+                    .find(|(i, _, _)| Some(i) == self.current_init.as_ref())
+                {
+                    self.files.push(init_file.clone());
+                }
+            }
+            self.files.push(file_name.clone());
+            if self.manifest.is_completed(index, segment, &self.dir) {
+                completed += 1;
+                continue;
+            }
+            let job = SegmentJob {
+                file_name: file_name.clone(),
+                status: SegmentStatus::Pending,
+                size: None,
+                sha256: None,
+                segment: segment.clone(),
+            };
+            if index < self.manifest.segments.len() {
+                self.manifest.segments[index] = job;
+            } else {
+                self.manifest.segments.push(job);
+            }
+            let decryption = segment.key.as_ref().map(|k| {
+                let patterns = self
+                    .init_files
+                    .iter()
+                    .find(|(i, _, _)| Some(i) == segment.init.as_ref())
+                    .map(|(_, _, p)| p.clone())
+                    .unwrap_or_default();
+                key_decryption(k, segment.sequence, &self.keys, patterns)
+            });
+            fragments.push((
+                index,
+                HLSFragmentHandler::new(
+                    segment.uri.clone(),
+                    segment.byte_range.clone(),
+                    file_name,
+                    self.dir.clone(),
+                    decryption,
+                    config,
+                ),
+            ));
+        }
+        if completed > 0 {
+            println!("Skip {} completed segments", completed);
+        }
+        self.manifest.save(&self.dir)?;
+
+        // Fragments are submitted in playlist order, jobs = 1 downloads them one by one
+        let indexes: Vec<usize> = fragments.iter().map(|(index, _)| *index).collect();
         let tasks: Vec<_> = fragments
             .into_iter()
-            .map(|frag| {
-                tokio::spawn(async move {
-                    frag.download_and_write().await;
-                })
-            })
+            .enumerate()
+            .map(|(position, (_, frag))| (position, frag.url.clone(), frag.download_and_write()))
             .collect();
-        for task in tasks {
-            task.await.unwrap();
+        let mut progress = OrderedProgress::build(tasks.len());
+        let mut first_error = None;
+        run_pool(tasks, config.jobs, config.host_jobs, |position, result| {
+            let result =
+                result.unwrap_or_else(|e| Err(Error::Io(format!("Download task failed: {}", e))));
+            if let Err(e) = self.complete_job(indexes[position], result) {
+                println!("{}", e);
+                first_error.get_or_insert(e);
+            }
+            progress.complete(position);
+            println!(
+                "Downloaded {}/{} segments, {} in order",
+                progress.completed,
+                progress.total(),
+                progress.in_order
+            );
+        })
+        .await;
+        self.save_manifest();
+
+        let failed = self.manifest.failed_count();
+        if failed > 0 {
+            println!(
+                "{} segments are not downloaded, continue with --resume {}",
+                failed, self.dir
+            );
         }
-    } else {
-        for f in fragments {
-            f.download_and_write().await;
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
 
-    let list_file_data = downloaded_file.as_bytes();
-    write_data_file(list_file_data, dir.as_ref(), list_file);
-    // let _ = try_join_all(tasks.iter()).await;
-    // Create output video file name
-    let mut output_video_name: String;
-    match output {
-        None => {
-            output_video_name = dir[10..dir.len() - 1].to_string();
+    // Leave the failed segments out of the output
+    pub fn skip_failed(&mut self) {
+        let failed: HashSet<&str> = self
+            .manifest
+            .segments
+            .iter()
+            .filter(|j| j.status == SegmentStatus::Failed)
+            .map(|j| j.file_name.as_str())
+            .collect();
+        self.files.retain(|f| !failed.contains(f.as_str()));
+    }
+
+    // Record the result of a segment so a resumed download can skip it
+    fn complete_job(&mut self, index: usize, result: Result<(u64, String)>) -> Result<()> {
+        let job = &mut self.manifest.segments[index];
+        let result = match result {
+            Ok((size, sha256)) => {
+                job.status = SegmentStatus::Completed;
+                job.size = Some(size);
+                job.sha256 = Some(sha256);
+                Ok(())
+            }
+            Err(e) => {
+                job.status = SegmentStatus::Failed;
+                Err(e)
+            }
+        };
+        if self.manifest_saved.elapsed() >= MANIFEST_SAVE_INTERVAL {
+            self.save_manifest();
         }
-        Some(name) => {
-            output_video_name = name;
+        result
+    }
+
+    fn save_manifest(&mut self) {
+        if let Err(e) = self.manifest.save(&self.dir) {
+            println!("{}", e);
         }
+        self.manifest_saved = Instant::now();
     }
-    output_video_name.push_str(".mp4");
+
+    // Join downloaded files into the output video
+    pub fn finish(self, config: HLSConfig<'_>) -> Result<()> {
+        let dir = self.dir;
+        if self.files.is_empty() {
+            println!("No segment is downloaded");
+            if !config.keep {
+                remove_download_folder(&dir)?;
+            }
+            return Ok(());
+        }
+        // Create output video file name
+        let output_video_name = match config.output {
+            None => default_output_name(&dir),
+            Some(name) => name,
+        };
+        let result = if config.ffmpeg {
+            join_with_ffmpeg(&dir, &self.files, self.fmp4, &output_video_name)
+        } else if self.fmp4 || !config.mp4 {
+            let extension = if self.fmp4 { ".mp4" } else { ".ts" };
+            let output_path = PathBuf::from(output_video_name + extension);
+            concat_files(&dir, &self.files, &output_path)
+        } else {
+            let joined_path = PathBuf::from(&dir).join("joined.ts");
+            concat_files(&dir, &self.files, &joined_path).and_then(|_| {
+                let output_path = PathBuf::from(output_video_name + ".mp4");
+                remux_ts_to_mp4(&joined_path, &output_path)
+            })
+        };
+
+        // Keep downloaded files when joining failed
+        if result.is_err() {
+            println!(
+                "Cannot create output video, downloaded files are kept in {}",
+                dir
+            );
+        } else if !config.keep {
+            remove_download_folder(&dir)?;
+        }
+        result
+    }
+}
+
+// Output is named after the timestamp of the working folder
+fn default_output_name(dir: &str) -> String {
+    let folder = Path::new(dir)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match folder.strip_prefix("sai-output") {
+        Some(timestamp) if !timestamp.is_empty() => timestamp.to_string(),
+        _ => folder,
+    }
+}
+
+fn join_with_ffmpeg(
+    dir: &str,
+    files: &[String],
+    fmp4: bool,
+    output_video_name: &str,
+) -> Result<()> {
+    let input_args = if fmp4 {
+        let joined_path = PathBuf::from(dir).join("joined.mp4");
+        concat_files(dir, files, &joined_path)?;
+        format!("-i {}", joined_path.display())
+    } else {
+        let list_file = "list.txt";
+        let mut downloaded_file = String::new();
+        for file_name in files {
+            downloaded_file.push_str("file ./");
+            downloaded_file.push_str(file_name);
+            downloaded_file.push('\n');
+        }
+        write_data_file(downloaded_file.as_bytes(), dir, list_file)?;
+        format!("-f concat -safe 0 -i {}{}", dir, list_file)
+    };
 
     // Create command string
-    let mut ffmpeg_cmd = String::from("ffmpeg -f concat -safe 0 -i ");
-    ffmpeg_cmd.push_str(&dir);
-    ffmpeg_cmd.push_str(list_file);
+    let mut ffmpeg_cmd = String::from("ffmpeg ");
+    ffmpeg_cmd.push_str(&input_args);
     ffmpeg_cmd.push_str(" -c copy ");
-    ffmpeg_cmd.push_str(&output_video_name);
-
-    // Run command and delete temp folder
-    run_os_command(&ffmpeg_cmd);
-    if !keep {
-        remove_download_folder(&dir);
+    ffmpeg_cmd.push_str(output_video_name);
+    ffmpeg_cmd.push_str(".mp4");
+    match run_os_command(&ffmpeg_cmd) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::Ffmpeg(format!("Command failed: {}", ffmpeg_cmd))),
+        Err(e) => Err(Error::Ffmpeg(format!("Cannot run ffmpeg: {}", e))),
     }
 }
 
 pub struct HLSFragmentHandler {
     url: String,
-    headers: Option<HeaderMap>,
+    byte_range: Option<ByteRange>,
+    client: HttpClient,
     png: bool,
-    h2: bool,
     file_name: String,
     dir: String,
     delay: Option<u64>,
-    retry: Option<u8>,
+    retry: RetryPolicy,
+    decryption: Option<Decryption>,
 }
 
 impl HLSFragmentHandler {
     fn new(
         url: String,
-        headers: Option<HeaderMap>,
-        png: bool,
-        h2: bool,
+        byte_range: Option<ByteRange>,
         file_name: String,
         dir: String,
-        delay: Option<u64>,
-        retry: Option<u8>,
+        decryption: Option<Decryption>,
+        config: &HLSConfig,
     ) -> Self {
         Self {
             url,
-            headers,
-            png,
-            h2,
+            byte_range,
+            client: config.client.clone(),
+            png: config.png,
             file_name,
             dir,
-            delay,
-            retry,
+            delay: config.delay,
+            retry: config.retry,
+            decryption,
         }
     }
 
-    async fn download_and_write(self) {
-        let mut data =
-            get_response_bytes(&self.url, &self.headers, self.h2, self.delay, self.retry)
-                .await
-                .unwrap();
+    // Size and checksum of the written file
+    async fn download_and_write(self) -> Result<(u64, String)> {
+        let mut data = get_response_range_bytes(
+            &self.url,
+            self.byte_range.as_ref(),
+            &self.client,
+            self.delay,
+            self.retry,
+        )
+        .await?;
         if self.png {
             data = strip_png(data);
         }
-        write_data_file(&data, &self.dir, &self.file_name);
+        if let Some(decryption) = &self.decryption {
+            data = match decryption.decrypt(&data) {
+                Ok(d) => Bytes::from(d),
+                Err(Error::Parse(message)) => {
+                    return Err(Error::Parse(format!("{} {}", message, self.url)));
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        overwrite_data_file(&data, &self.dir, &self.file_name)?;
+        Ok((data.len() as u64, checksum(&data)))
     }
 }
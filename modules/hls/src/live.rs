@@ -0,0 +1,120 @@
+use crate::playlist::{MediaPlaylist, MediaSegment};
+use crate::{load_playlist, HLSConfig, HLSDownload};
+use saidl_helper::error::{Error, Result};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+
+// Reload interval when the playlist has no target duration
+const DEFAULT_TARGET_DURATION: u64 = 10;
+
+// Reload a live media playlist and download new segments until the stream ends,
+// the recorded duration reaches max_duration seconds or Ctrl-C is pressed. A second Ctrl-C
+// aborts without joining, the folder is kept so the recording can be resumed.
+pub async fn record_live(
+    input: &str,
+    base_url: Option<&str>,
+    config: HLSConfig<'_>,
+    max_duration: Option<u64>,
+) -> Result<()> {
+    let mut playlist = load_playlist(input, base_url, &config).await?;
+    // Reload the media playlist directly when a variant is selected
+    let source = playlist.url.clone().unwrap_or_else(|| input.to_string());
+
+    let (stop_sender, mut stop_receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Stop recording after downloading current segments, Ctrl-C again to abort");
+            let _ = stop_sender.send(true);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Recording is aborted");
+            std::process::exit(130);
+        }
+    });
+
+    let mut session = HLSDownload::build(Some(source.clone()))?;
+    let mut last_sequence: Option<u64> = None;
+    let mut recorded = 0.0;
+    loop {
+        let segments = new_segments(&playlist, last_sequence, max_duration, recorded);
+        if let (Some(last), Some(first)) = (last_sequence, segments.first()) {
+            if first.sequence > last + 1 {
+                println!("Missed {} segments", first.sequence - last - 1);
+            }
+        }
+        if let Some(last) = segments.last() {
+            // Folder is kept with its job manifest so the recording can be resumed
+            match session.download_segments(segments, &config).await {
+                Ok(()) => {}
+                // Segments leave the sliding window, so a failed one is not tried again
+                Err(e @ (Error::Network(_) | Error::HttpStatus { .. })) => {
+                    println!("Skip failed segments of the live stream: {}", e);
+                    session.skip_failed();
+                }
+                // The recorded part is still joined before the error is returned
+                Err(e) => {
+                    if let Err(finish_error) = session.finish(config) {
+                        println!("{}", finish_error);
+                    }
+                    return Err(e);
+                }
+            }
+            last_sequence = Some(last.sequence);
+            recorded += segments.iter().map(|s| s.duration as f64).sum::<f64>();
+            println!("Recorded {:.1} seconds", recorded);
+        }
+        if playlist.end_list {
+            println!("Live stream is ended");
+            break;
+        }
+        if max_duration.is_some_and(|max| recorded >= max as f64) {
+            println!("Duration limit is reached");
+            break;
+        }
+        if *stop_receiver.borrow() {
+            break;
+        }
+
+        // Wait half of target duration when the playlist has not changed
+        let target = playlist.target_duration.unwrap_or(DEFAULT_TARGET_DURATION);
+        let wait = if segments.is_empty() {
+            Duration::from_millis(target * 500)
+        } else {
+            Duration::from_secs(target)
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => {}
+            Ok(_) = stop_receiver.changed() => break,
+        }
+        match load_playlist(&source, base_url, &config).await {
+            Ok(p) => playlist = p,
+            Err(e) => println!("Cannot reload playlist, retry later: {}", e),
+        }
+    }
+    session.finish(config)
+}
+
+// Segments after last downloaded sequence, limited by the remaining duration
+pub fn new_segments(
+    playlist: &MediaPlaylist,
+    last_sequence: Option<u64>,
+    max_duration: Option<u64>,
+    recorded: f64,
+) -> &[MediaSegment] {
+    let start = playlist
+        .segments
+        .iter()
+        .position(|s| last_sequence.is_none_or(|last| s.sequence > last))
+        .unwrap_or(playlist.segments.len());
+    let mut end = start;
+    let mut duration = recorded;
+    for segment in &playlist.segments[start..] {
+        if max_duration.is_some_and(|max| duration >= max as f64) {
+            break;
+        }
+        duration += segment.duration as f64;
+        end += 1;
+    }
+    &playlist.segments[start..end]
+}
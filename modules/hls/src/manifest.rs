@@ -0,0 +1,75 @@
+use crate::playlist::MediaSegment;
+use saidl_helper::error::{Error, Result};
+use saidl_helper::file::{io_error, overwrite_data_file};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SegmentStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+// Download state of one segment in the working folder
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegmentJob {
+    pub file_name: String,
+    pub status: SegmentStatus,
+    pub size: Option<u64>,
+
+    // Hex SHA-256 of the written file
+    pub sha256: Option<String>,
+    pub segment: MediaSegment,
+}
+
+// Job manifest kept in the working folder so an interrupted download can be resumed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JobManifest {
+    pub playlist_url: Option<String>,
+    pub segments: Vec<SegmentJob>,
+}
+
+impl JobManifest {
+    pub fn load(dir: &str) -> Result<Self> {
+        let path = Path::new(dir).join(MANIFEST_FILE);
+        let content = fs::read_to_string(&path)
+            .map_err(|e| io_error("Cannot read job manifest", &path, e))?;
+        toml::from_str(&content)
+            .map_err(|e| Error::Parse(format!("Invalid job manifest {}: {}", path.display(), e)))
+    }
+
+    // Written to a temporary file then renamed, an interrupted save keeps the previous manifest
+    pub fn save(&self, dir: &str) -> Result<()> {
+        let content = toml::to_string(self)
+            .map_err(|e| Error::Parse(format!("Cannot write job manifest: {}", e)))?;
+        overwrite_data_file(content.as_bytes(), dir, MANIFEST_FILE)
+    }
+
+    // Completed job of the segment whose file still matches its checksum
+    pub fn is_completed(&self, index: usize, segment: &MediaSegment, dir: &str) -> bool {
+        let job = match self.segments.get(index) {
+            Some(j) if j.status == SegmentStatus::Completed && &j.segment == segment => j,
+            _ => return false,
+        };
+        match fs::read(Path::new(dir).join(&job.file_name)) {
+            Ok(data) => job.sha256.as_deref() == Some(checksum(&data).as_str()),
+            Err(_) => false,
+        }
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|j| j.status == SegmentStatus::Failed)
+            .count()
+    }
+}
+
+pub fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
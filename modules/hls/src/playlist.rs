@@ -0,0 +1,519 @@
+use crate::crypto::{parse_hex_key, KEY_SIZE};
+use reqwest::Url;
+use saidl_helper::error::Error;
+use saidl_helper::import::ImportedRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+const PLAYLIST_HEADER: &str = "#EXTM3U";
+
+pub struct MediaPlaylist {
+    // Url the media playlist is fetched from
+    pub url: Option<String>,
+    pub target_duration: Option<u64>,
+    pub media_sequence: u64,
+    pub segments: Vec<MediaSegment>,
+    pub end_list: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MediaSegment {
+    pub uri: String,
+    pub duration: f32,
+    pub title: Option<String>,
+
+    // Media sequence number of this segment
+    pub sequence: u64,
+
+    // Encryption key from the latest EXT-X-KEY tag
+    pub key: Option<SegmentKey>,
+
+    // Initialization section from the latest EXT-X-MAP tag
+    pub init: Option<InitSection>,
+
+    // Sub-range of the uri from EXT-X-BYTERANGE
+    pub byte_range: Option<ByteRange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InitSection {
+    pub uri: String,
+    pub byte_range: Option<ByteRange>,
+
+    // Key in effect when the EXT-X-MAP tag appears
+    pub key: Option<SegmentKey>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KeyMethod {
+    Aes128,
+    SampleAes,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SegmentKey {
+    pub method: KeyMethod,
+    pub uri: String,
+    pub iv: Option<[u8; KEY_SIZE]>,
+}
+
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Default)]
+pub struct VariantFilter {
+    // Pick highest bandwidth by default
+    pub lowest_bandwidth: bool,
+    pub resolution: Option<(u32, u32)>,
+    pub max_height: Option<u32>,
+    pub codec: Option<String>,
+}
+
+impl VariantFilter {
+    pub fn select<'a>(&self, variants: &'a [Variant]) -> Option<&'a Variant> {
+        let candidates = variants.iter().filter(|v| self.matches(v));
+        if self.lowest_bandwidth {
+            candidates.min_by_key(|v| v.bandwidth)
+        } else {
+            candidates.max_by_key(|v| v.bandwidth)
+        }
+    }
+
+    fn matches(&self, variant: &Variant) -> bool {
+        if self.resolution.is_some() && variant.resolution != self.resolution {
+            return false;
+        }
+        if let Some(max_height) = self.max_height {
+            match variant.resolution {
+                Some((_, height)) if height <= max_height => {}
+                _ => return false,
+            }
+        }
+        if let Some(codec) = &self.codec {
+            match &variant.codecs {
+                Some(codecs) if codecs.contains(codec.as_str()) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bps", self.bandwidth)?;
+        if let Some((width, height)) = self.resolution {
+            write!(f, ", {}x{}", width, height)?;
+        }
+        if let Some(codecs) = &self.codecs {
+            write!(f, ", {}", codecs)?;
+        }
+        write!(f, " - {}", self.uri)
+    }
+}
+
+impl MediaPlaylist {
+    pub fn urls(&self) -> Vec<String> {
+        self.segments.iter().map(|s| s.uri.clone()).collect()
+    }
+}
+
+pub fn is_playlist(content: &str) -> bool {
+    content.trim_start().starts_with(PLAYLIST_HEADER)
+}
+
+pub fn is_master_playlist(content: &str) -> bool {
+    content
+        .lines()
+        .any(|l| l.trim_start().starts_with("#EXT-X-STREAM-INF"))
+}
+
+pub fn parse_master_playlist(content: &str, base_url: Option<&Url>) -> Result<Vec<Variant>, Error> {
+    if !is_playlist(content) {
+        return Err(Error::Parse(format!(
+            "Playlist must start with {}",
+            PLAYLIST_HEADER
+        )));
+    }
+    let mut variants = Vec::new();
+    // EXT-X-STREAM-INF applies to the next URI line
+    let mut next_attributes: Option<HashMap<String, String>> = None;
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = split_tag(tag);
+            if name == "EXT-X-STREAM-INF" {
+                next_attributes = Some(parse_attributes(value));
+            }
+            continue;
+        }
+        let attributes = match next_attributes.take() {
+            Some(a) => a,
+            None => continue,
+        };
+        let bandwidth = match attributes.get("BANDWIDTH") {
+            Some(b) => parse_number("BANDWIDTH", b)?,
+            None => {
+                return Err(Error::Parse(format!(
+                    "Missing BANDWIDTH for variant {}",
+                    line
+                )));
+            }
+        };
+        let resolution = match attributes.get("RESOLUTION") {
+            Some(r) => Some(parse_resolution(r).map_err(Error::Parse)?),
+            None => None,
+        };
+        variants.push(Variant {
+            uri: resolve_uri(line, base_url),
+            bandwidth,
+            resolution,
+            codecs: attributes.get("CODECS").cloned(),
+        });
+    }
+    Ok(variants)
+}
+
+// Resolution in WIDTHxHEIGHT format, such as 1280x720
+pub fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid resolution: {}", value);
+    let (width, height) = value.split_once(['x', 'X']).ok_or_else(invalid)?;
+    let width = width.trim().parse().map_err(|_| invalid())?;
+    let height = height.trim().parse().map_err(|_| invalid())?;
+    Ok((width, height))
+}
+
+pub fn parse_media_playlist(content: &str, base_url: Option<&Url>) -> Result<MediaPlaylist, Error> {
+    if !is_playlist(content) {
+        return Err(Error::Parse(format!(
+            "Playlist must start with {}",
+            PLAYLIST_HEADER
+        )));
+    }
+    let mut playlist = MediaPlaylist {
+        url: None,
+        target_duration: None,
+        media_sequence: 0,
+        segments: Vec::new(),
+        end_list: false,
+    };
+    // EXTINF and EXT-X-BYTERANGE apply to the next URI line
+    let mut next_info: Option<(f32, Option<String>)> = None;
+    let mut next_range: Option<(u64, Option<u64>)> = None;
+    // Sub-range without offset starts where the previous sub-range of the same uri ends
+    let mut previous_range_end: Option<(String, u64)> = None;
+    // EXT-X-KEY and EXT-X-MAP apply to all following segments until the next one
+    let mut current_key: Option<SegmentKey> = None;
+    let mut current_init: Option<InitSection> = None;
+    // Consecutive EXT-X-KEY tags list the same key in other formats, such as FairPlay
+    let mut key_tags_follow = false;
+    let mut unsupported_format: Option<String> = None;
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = split_tag(tag);
+            match name {
+                "EXTINF" => next_info = Some(parse_extinf(value)?),
+                "EXT-X-TARGETDURATION" => {
+                    playlist.target_duration = Some(parse_number(name, value)?);
+                }
+                "EXT-X-MEDIA-SEQUENCE" => {
+                    playlist.media_sequence = parse_number(name, value)?;
+                }
+                "EXT-X-BYTERANGE" => next_range = Some(parse_byte_range(value)?),
+                "EXT-X-KEY" => {
+                    if !key_tags_follow {
+                        current_key = None;
+                        unsupported_format = None;
+                    }
+                    key_tags_follow = true;
+                    match unsupported_key_format(value) {
+                        Some(format) => unsupported_format = Some(format),
+                        None => current_key = parse_key(value, base_url)?,
+                    }
+                }
+                "EXT-X-MAP" => {
+                    current_init = Some(parse_map(value, base_url, current_key.clone())?);
+                }
+                "EXT-X-ENDLIST" => playlist.end_list = true,
+                // Comments and unsupported tags
+                _ => {}
+            }
+            continue;
+        }
+        key_tags_follow = false;
+        if let (None, Some(format)) = (&current_key, &unsupported_format) {
+            return Err(Error::Parse(format!(
+                "Unsupported key format {}, only identity keys can be used",
+                format
+            )));
+        }
+        let (duration, title) = next_info.take().unwrap_or((0.0, None));
+        let uri = resolve_uri(line, base_url);
+        let byte_range = match next_range.take() {
+            Some((length, offset)) => {
+                let offset = match (offset, &previous_range_end) {
+                    (Some(o), _) => o,
+                    (None, Some((previous_uri, end))) if *previous_uri == uri => *end,
+                    (None, _) => {
+                        return Err(Error::Parse(format!(
+                            "Missing byte range offset for {}",
+                            uri
+                        )));
+                    }
+                };
+                let end = offset
+                    .checked_add(length)
+                    .ok_or_else(|| Error::Parse(format!("Byte range of {} is too large", uri)))?;
+                previous_range_end = Some((uri.clone(), end));
+                Some(ByteRange { length, offset })
+            }
+            None => {
+                previous_range_end = None;
+                None
+            }
+        };
+        playlist.segments.push(MediaSegment {
+            uri,
+            duration,
+            title,
+            sequence: playlist.media_sequence + playlist.segments.len() as u64,
+            key: current_key.clone(),
+            init: current_init.clone(),
+            byte_range,
+        });
+    }
+    Ok(playlist)
+}
+
+// Plain text input, one segment url per line
+pub fn parse_link_list(lines: impl Iterator<Item = String>) -> MediaPlaylist {
+    let segments = lines
+        .filter(|l| l.starts_with("http"))
+        .enumerate()
+        .map(|(index, uri)| MediaSegment {
+            uri,
+            duration: 0.0,
+            title: None,
+            sequence: index as u64,
+            key: None,
+            init: None,
+            byte_range: None,
+        })
+        .collect();
+    MediaPlaylist {
+        url: None,
+        target_duration: None,
+        media_sequence: 0,
+        segments,
+        end_list: true,
+    }
+}
+
+const SEGMENT_EXTENSIONS: [&str; 8] = ["ts", "m4s", "mp4", "m4v", "m4a", "aac", "cmfv", "cmfa"];
+
+// Request of the media in a HAR capture whose headers are reused. Without an m3u8
+// request, the segment requests in the recorded order are returned as a link list.
+pub fn har_media(
+    requests: Vec<ImportedRequest>,
+) -> Option<(ImportedRequest, Option<MediaPlaylist>)> {
+    let extension = |request: &ImportedRequest| {
+        Url::parse(&request.url)
+            .ok()
+            .and_then(|u| {
+                u.path()
+                    .rsplit_once('.')
+                    .map(|(_, e)| e.to_ascii_lowercase())
+            })
+            .unwrap_or_default()
+    };
+    let mime_type = |request: &ImportedRequest| request.mime_type.clone().unwrap_or_default();
+    let is_playlist =
+        |r: &ImportedRequest| extension(r) == "m3u8" || mime_type(r).contains("mpegurl");
+    let is_segment = |r: &ImportedRequest| {
+        SEGMENT_EXTENSIONS.contains(&extension(r).as_str())
+            || [
+                "video/mp2t",
+                "video/iso.segment",
+                "video/mp4",
+                "audio/mp4",
+                "audio/aac",
+            ]
+            .contains(&mime_type(r).as_str())
+    };
+
+    if requests.iter().any(is_playlist) {
+        return requests.into_iter().find(is_playlist).map(|r| (r, None));
+    }
+    let mut urls: Vec<String> = Vec::new();
+    let mut first = None;
+    for request in requests.into_iter().filter(is_segment) {
+        if urls.contains(&request.url) {
+            continue;
+        }
+        urls.push(request.url.clone());
+        first.get_or_insert(request);
+    }
+    first.map(|r| (r, Some(parse_link_list(urls.into_iter()))))
+}
+
+pub fn resolve_uri(uri: &str, base_url: Option<&Url>) -> String {
+    match base_url {
+        Some(base) => match base.join(uri) {
+            Ok(url) => url.to_string(),
+            Err(_) => uri.to_string(),
+        },
+        None => uri.to_string(),
+    }
+}
+
+fn split_tag(tag: &str) -> (&str, &str) {
+    match tag.split_once(':') {
+        Some((name, value)) => (name, value.trim()),
+        None => (tag, ""),
+    }
+}
+
+// Parse attribute list such as BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"
+pub fn parse_attributes(value: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (name, after_name) = match rest.split_once('=') {
+            Some(pair) => pair,
+            None => break,
+        };
+        let (attr_value, after_value) = match after_name.strip_prefix('"') {
+            // Quoted string may contain commas
+            Some(quoted) => match quoted.split_once('"') {
+                Some((v, after)) => (v, after),
+                None => (quoted, ""),
+            },
+            None => after_name.split_once(',').unwrap_or((after_name, "")),
+        };
+        attributes.insert(name.trim().to_string(), attr_value.to_string());
+        rest = after_value.trim_start_matches(',').trim_start();
+    }
+    attributes
+}
+
+// Keys of DRM systems cannot be fetched from their uri
+fn unsupported_key_format(value: &str) -> Option<String> {
+    parse_attributes(value)
+        .remove("KEYFORMAT")
+        .filter(|format| format != "identity")
+}
+
+fn parse_key(value: &str, base_url: Option<&Url>) -> Result<Option<SegmentKey>, Error> {
+    let attributes = parse_attributes(value);
+    let method = match attributes.get("METHOD").map(String::as_str) {
+        Some("NONE") => return Ok(None),
+        Some("AES-128") => KeyMethod::Aes128,
+        Some("SAMPLE-AES") => KeyMethod::SampleAes,
+        Some(m) => {
+            return Err(Error::Parse(format!(
+                "Unsupported encryption method: {}",
+                m
+            )));
+        }
+        None => {
+            return Err(Error::Parse(format!(
+                "Missing METHOD in EXT-X-KEY: {}",
+                value
+            )));
+        }
+    };
+    let uri = match attributes.get("URI") {
+        Some(uri) => resolve_uri(uri, base_url),
+        None => {
+            return Err(Error::Parse(format!("Missing URI in EXT-X-KEY: {}", value)));
+        }
+    };
+    let iv = match attributes.get("IV") {
+        Some(iv) => Some(parse_hex_key(iv).map_err(Error::Parse)?),
+        None => None,
+    };
+    Ok(Some(SegmentKey { method, uri, iv }))
+}
+
+fn parse_map(
+    value: &str,
+    base_url: Option<&Url>,
+    key: Option<SegmentKey>,
+) -> Result<InitSection, Error> {
+    let attributes = parse_attributes(value);
+    let uri = match attributes.get("URI") {
+        Some(uri) => resolve_uri(uri, base_url),
+        None => {
+            return Err(Error::Parse(format!("Missing URI in EXT-X-MAP: {}", value)));
+        }
+    };
+    let byte_range = match attributes.get("BYTERANGE") {
+        Some(range) => {
+            let (length, offset) = parse_byte_range(range)?;
+            Some(ByteRange {
+                length,
+                offset: offset.unwrap_or(0),
+            })
+        }
+        None => None,
+    };
+    Ok(InitSection {
+        uri,
+        byte_range,
+        key,
+    })
+}
+
+// Byte range in <length>[@<offset>] format
+fn parse_byte_range(value: &str) -> Result<(u64, Option<u64>), Error> {
+    let (length, offset) = match value.split_once('@') {
+        Some((length, offset)) => (length, Some(offset)),
+        None => (value, None),
+    };
+    let length = parse_number("byte range length", length.trim())?;
+    if length == 0 {
+        return Err(Error::Parse(format!(
+            "Byte range length cannot be zero: {}",
+            value
+        )));
+    }
+    let offset = match offset {
+        Some(o) => Some(parse_number("byte range offset", o.trim())?),
+        None => None,
+    };
+    if offset.is_some_and(|o| o.checked_add(length).is_none()) {
+        return Err(Error::Parse(format!("Byte range is too large: {}", value)));
+    }
+    Ok((length, offset))
+}
+
+fn parse_extinf(value: &str) -> Result<(f32, Option<String>), Error> {
+    let (duration, title) = value.split_once(',').unwrap_or((value, ""));
+    let duration = duration
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| Error::Parse(format!("Invalid EXTINF duration: {}", value)))?;
+    let title = title.trim();
+    let title = if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    };
+    Ok((duration, title))
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, Error> {
+    value
+        .parse::<u64>()
+        .map_err(|_| Error::Parse(format!("Invalid {} value: {}", name, value)))
+}
@@ -0,0 +1,83 @@
+use futures::stream::{self, StreamExt};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+
+// Concurrent downloads of --multi-thread when --jobs is not set
+pub const DEFAULT_JOBS: usize = 8;
+
+// Run tasks with at most `jobs` at once and at most `host_jobs` per host of their url.
+// Each result is passed to on_complete with the task index as soon as it finishes,
+// jobs = 1 runs the tasks one by one in order.
+pub async fn run_pool<T, F>(
+    tasks: Vec<(usize, String, F)>,
+    jobs: usize,
+    host_jobs: Option<usize>,
+    mut on_complete: impl FnMut(usize, Result<T, JoinError>),
+) where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut hosts: HashMap<String, Arc<Semaphore>> = HashMap::new();
+    let workers: Vec<_> = tasks
+        .into_iter()
+        .map(|(index, url, task)| {
+            let host_limit = host_jobs.map(|limit| {
+                let host = Url::parse(&url)
+                    .ok()
+                    .and_then(|u| u.host_str().map(String::from))
+                    .unwrap_or_default();
+                hosts
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+                    .clone()
+            });
+            async move {
+                let _permit = match &host_limit {
+                    Some(semaphore) => semaphore.acquire().await.ok(),
+                    None => None,
+                };
+                (index, tokio::spawn(task).await)
+            }
+        })
+        .collect();
+    let mut results = stream::iter(workers).buffer_unordered(jobs.max(1));
+    while let Some((index, result)) = results.next().await {
+        on_complete(index, result);
+    }
+}
+
+// Completed tasks and the length of the completed prefix in submission order
+pub struct OrderedProgress {
+    done: Vec<bool>,
+    pub completed: usize,
+    pub in_order: usize,
+}
+
+impl OrderedProgress {
+    pub fn build(total: usize) -> Self {
+        Self {
+            done: vec![false; total],
+            completed: 0,
+            in_order: 0,
+        }
+    }
+
+    pub fn complete(&mut self, position: usize) {
+        if position >= self.done.len() || self.done[position] {
+            return;
+        }
+        self.done[position] = true;
+        self.completed += 1;
+        while self.in_order < self.done.len() && self.done[self.in_order] {
+            self.in_order += 1;
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.done.len()
+    }
+}
@@ -0,0 +1,883 @@
+use crate::ts::{
+    nal_units, packet_payload_offset, packet_pid, parse_pat, parse_pmt, psi_section,
+    remove_emulation_prevention, STREAM_TYPE_AAC, STREAM_TYPE_H264, TS_PACKET_SIZE, TS_SYNC_BYTE,
+};
+use saidl_helper::error::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const VIDEO_TIMESCALE: u32 = 90000;
+const MOVIE_TIMESCALE: u32 = 1000;
+const AAC_FRAME_SAMPLES: u32 = 1024;
+const TIMESTAMP_WRAP: i64 = 1 << 33;
+// Frame duration when timestamps are missing, 30 fps in 90 kHz
+const DEFAULT_FRAME_DURATION: i64 = 3000;
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+// Remux H.264 and AAC streams of a MPEG-TS file into a MP4 file
+pub fn remux_ts_to_mp4(input: &Path, output: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(input).map_err(io_error)?);
+    let mut writer = BufWriter::new(File::create(output).map_err(io_error)?);
+
+    // Sample data is written while demuxing, the 64 bit mdat size is patched at the end
+    let ftyp = ftyp();
+    writer.write_all(&ftyp).map_err(io_error)?;
+    let mdat_start = ftyp.len() as u64;
+    writer
+        .write_all(&[0, 0, 0, 1, b'm', b'd', b'a', b't', 0, 0, 0, 0, 0, 0, 0, 0])
+        .map_err(io_error)?;
+    let mut muxer = Muxer {
+        writer,
+        position: mdat_start + 16,
+        pmt_pids: Vec::new(),
+        tracks: Vec::new(),
+        last_track: None,
+    };
+    let mut packet = [0u8; TS_PACKET_SIZE];
+    loop {
+        match reader.read_exact(&mut packet) {
+            Ok(_) => muxer.push_packet(&packet)?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+    for index in 0..muxer.tracks.len() {
+        muxer.flush_pes(index)?;
+    }
+
+    let Muxer {
+        mut writer,
+        position,
+        tracks,
+        ..
+    } = muxer;
+    let tracks: Vec<Track> = tracks
+        .into_iter()
+        .filter(|t| t.codec.is_some() && !t.samples.is_empty())
+        .collect();
+    if tracks.is_empty() {
+        return Err(Error::Parse("No H.264 or AAC stream to remux".to_string()));
+    }
+    writer
+        .seek(SeekFrom::Start(mdat_start + 8))
+        .map_err(io_error)?;
+    writer
+        .write_all(&(position - mdat_start).to_be_bytes())
+        .map_err(io_error)?;
+    writer.seek(SeekFrom::Start(position)).map_err(io_error)?;
+    writer.write_all(&moov(&tracks)).map_err(io_error)?;
+    writer.flush().map_err(io_error)
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::Io(format!("Remux failed: {}", e))
+}
+
+enum Codec {
+    H264 {
+        sps: Vec<u8>,
+        pps: Vec<u8>,
+        // Profile, constraint flags and level of the SPS
+        profile: [u8; 3],
+        width: u16,
+        height: u16,
+    },
+    Aac {
+        config: [u8; 2],
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+struct Sample {
+    size: u32,
+    sync: bool,
+    // Timestamps in 90 kHz
+    dts: i64,
+    pts: i64,
+}
+
+struct Track {
+    pid: u16,
+    video: bool,
+    codec: Option<Codec>,
+    samples: Vec<Sample>,
+    // Offset and sample count of each chunk
+    chunks: Vec<(u64, u32)>,
+    timestamps: TimestampUnwrapper,
+    // Parameter sets seen before the codec is known
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    pes: Vec<u8>,
+}
+
+// Continue timestamps after 33 bit wrap
+#[derive(Default)]
+struct TimestampUnwrapper {
+    offset: i64,
+    last: Option<i64>,
+}
+
+impl TimestampUnwrapper {
+    fn unwrap(&mut self, value: i64) -> i64 {
+        let mut result = value + self.offset;
+        if let Some(last) = self.last {
+            if result < last - TIMESTAMP_WRAP / 2 {
+                self.offset += TIMESTAMP_WRAP;
+                result += TIMESTAMP_WRAP;
+            }
+        }
+        self.last = Some(result);
+        result
+    }
+}
+
+struct Muxer<W: Write> {
+    writer: W,
+    position: u64,
+    pmt_pids: Vec<u16>,
+    tracks: Vec<Track>,
+    last_track: Option<usize>,
+}
+
+impl<W: Write> Muxer<W> {
+    fn push_packet(&mut self, packet: &[u8; TS_PACKET_SIZE]) -> Result<()> {
+        if packet[0] != TS_SYNC_BYTE {
+            return Err(Error::Parse("Input is not a MPEG-TS stream".to_string()));
+        }
+        let pid = packet_pid(packet);
+        let start = packet[1] & 0x40 != 0;
+        let payload = match packet_payload_offset(packet) {
+            Some(offset) => &packet[offset..],
+            None => return Ok(()),
+        };
+        if pid == 0 && start {
+            self.pmt_pids = parse_pat(payload);
+            return Ok(());
+        }
+        if self.pmt_pids.contains(&pid) && start {
+            // Streams are taken from the first PMT
+            if self.tracks.is_empty() {
+                if let Some(section) = psi_section(payload) {
+                    self.add_tracks(section);
+                }
+            }
+            return Ok(());
+        }
+        let index = match self.tracks.iter().position(|t| t.pid == pid) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        if start {
+            self.flush_pes(index)?;
+        }
+        self.tracks[index].pes.extend_from_slice(payload);
+        Ok(())
+    }
+
+    fn add_tracks(&mut self, section: &[u8]) {
+        for (stream_type, pid) in parse_pmt(section) {
+            let video = match stream_type {
+                STREAM_TYPE_H264 => true,
+                STREAM_TYPE_AAC => false,
+                _ => {
+                    println!(
+                        "Stream type 0x{:02x} of PID {} is not supported, skipped",
+                        stream_type, pid
+                    );
+                    continue;
+                }
+            };
+            self.tracks.push(Track {
+                pid,
+                video,
+                codec: None,
+                samples: Vec::new(),
+                chunks: Vec::new(),
+                timestamps: TimestampUnwrapper::default(),
+                sps: None,
+                pps: None,
+                pes: Vec::new(),
+            });
+        }
+    }
+
+    fn flush_pes(&mut self, index: usize) -> Result<()> {
+        let pes = std::mem::take(&mut self.tracks[index].pes);
+        if pes.len() < 9 || pes[..3] != [0, 0, 1] {
+            return Ok(());
+        }
+        let header_length = 9 + pes[8] as usize;
+        if header_length > pes.len() {
+            return Ok(());
+        }
+        let flags = pes[7] >> 6;
+        let pts = if flags & 0x2 != 0 && pes.len() >= 14 {
+            Some(read_timestamp(&pes[9..14]))
+        } else {
+            None
+        };
+        let dts = if flags == 0x3 && pes.len() >= 19 {
+            Some(read_timestamp(&pes[14..19]))
+        } else {
+            pts
+        };
+        let payload = &pes[header_length..];
+        if self.tracks[index].video {
+            self.push_video(index, pts, dts, payload)
+        } else {
+            self.push_audio(index, pts, payload)
+        }
+    }
+
+    fn push_video(
+        &mut self,
+        index: usize,
+        pts: Option<i64>,
+        dts: Option<i64>,
+        payload: &[u8],
+    ) -> Result<()> {
+        let track = &mut self.tracks[index];
+        let mut data = Vec::with_capacity(payload.len());
+        let mut sync = false;
+        for (start, end) in nal_units(payload) {
+            let nal = &payload[start..end];
+            match nal[0] & 0x1f {
+                // Parameter sets are stored in avcC
+                7 => {
+                    track.sps.get_or_insert_with(|| nal.to_vec());
+                    continue;
+                }
+                8 => {
+                    track.pps.get_or_insert_with(|| nal.to_vec());
+                    continue;
+                }
+                // Access unit delimiter
+                9 => continue,
+                5 => sync = true,
+                _ => {}
+            }
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        if track.codec.is_none() {
+            if let (Some(sps), Some(pps)) = (&track.sps, &track.pps) {
+                let invalid = || Error::Parse("Invalid H.264 sequence parameter set".to_string());
+                let profile = sps
+                    .get(1..4)
+                    .and_then(|p| p.try_into().ok())
+                    .ok_or_else(invalid)?;
+                let (width, height) = sps_dimensions(sps).ok_or_else(invalid)?;
+                track.codec = Some(Codec::H264 {
+                    sps: sps.clone(),
+                    pps: pps.clone(),
+                    profile,
+                    width,
+                    height,
+                });
+            }
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (dts, pts) = match (dts, pts) {
+            (Some(dts), Some(pts)) => {
+                let unwrapped = track.timestamps.unwrap(dts);
+                // Composition offset is small even when pts wraps before dts
+                let offset = (pts - dts).rem_euclid(TIMESTAMP_WRAP);
+                let offset = if offset > TIMESTAMP_WRAP / 2 {
+                    0
+                } else {
+                    offset
+                };
+                (unwrapped, unwrapped + offset)
+            }
+            _ => {
+                let dts = match track.samples.last() {
+                    Some(last) => last.dts + DEFAULT_FRAME_DURATION,
+                    None => 0,
+                };
+                (dts, dts)
+            }
+        };
+        self.write_sample(index, &data, sync, dts, pts)
+    }
+
+    fn push_audio(&mut self, index: usize, pts: Option<i64>, payload: &[u8]) -> Result<()> {
+        // Frames of a PES follow its timestamp, without one they follow the previous frame
+        let track = &mut self.tracks[index];
+        let start = pts.map(|pts| track.timestamps.unwrap(pts));
+        let mut frame_index = 0;
+        let mut position = 0;
+        while position + 7 <= payload.len() {
+            let header = &payload[position..];
+            if header[0] != 0xff || header[1] & 0xf0 != 0xf0 {
+                break;
+            }
+            let header_length = if header[1] & 0x01 == 0 { 9 } else { 7 };
+            let frame_length = (((header[3] & 0x03) as usize) << 11)
+                | ((header[4] as usize) << 3)
+                | ((header[5] as usize) >> 5);
+            if frame_length <= header_length || position + frame_length > payload.len() {
+                break;
+            }
+            let track = &mut self.tracks[index];
+            let frequency_index = (header[2] >> 2) & 0xf;
+            let sample_rate = match AAC_SAMPLE_RATES.get(frequency_index as usize) {
+                Some(rate) => *rate,
+                None => {
+                    return Err(Error::Parse(format!(
+                        "Invalid AAC sampling frequency index {}",
+                        frequency_index
+                    )));
+                }
+            };
+            if track.codec.is_none() {
+                let object_type = ((header[2] >> 6) & 0x3) + 1;
+                let channels = ((header[2] & 0x1) << 2) | (header[3] >> 6);
+                let config = ((object_type as u16) << 11)
+                    | ((frequency_index as u16) << 7)
+                    | ((channels as u16) << 3);
+                track.codec = Some(Codec::Aac {
+                    config: config.to_be_bytes(),
+                    sample_rate,
+                    channels: channels as u16,
+                });
+            }
+            let offset = frame_index * frame_duration(sample_rate);
+            let pts = match (start, track.samples.last()) {
+                (Some(start), _) => start + offset,
+                (None, Some(last)) => last.pts + frame_duration(sample_rate),
+                (None, None) => 0,
+            };
+            frame_index += 1;
+            let frame = payload[position + header_length..position + frame_length].to_vec();
+            self.write_sample(index, &frame, true, pts, pts)?;
+            position += frame_length;
+        }
+        Ok(())
+    }
+
+    fn write_sample(
+        &mut self,
+        index: usize,
+        data: &[u8],
+        sync: bool,
+        dts: i64,
+        pts: i64,
+    ) -> Result<()> {
+        let track = &mut self.tracks[index];
+        // Consecutive samples of the same track share a chunk
+        if self.last_track != Some(index) {
+            track.chunks.push((self.position, 0));
+            self.last_track = Some(index);
+        }
+        if let Some(chunk) = track.chunks.last_mut() {
+            chunk.1 += 1;
+        }
+        track.samples.push(Sample {
+            size: data.len() as u32,
+            sync,
+            dts,
+            pts,
+        });
+        self.writer.write_all(data).map_err(io_error)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+}
+
+fn read_timestamp(data: &[u8]) -> i64 {
+    (((data[0] as i64 >> 1) & 0x07) << 30)
+        | ((data[1] as i64) << 22)
+        | ((data[2] as i64 >> 1) << 15)
+        | ((data[3] as i64) << 7)
+        | (data[4] as i64 >> 1)
+}
+
+// Bit reader for exp-Golomb coded fields
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit as u32)
+    }
+
+    fn bits(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()?;
+        }
+        Some(value)
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()? as i64;
+        let signed = if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -(value / 2)
+        };
+        Some(signed as i32)
+    }
+}
+
+// Width and height from H.264 sequence parameter set
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let data = remove_emulation_prevention(sps);
+    let mut reader = BitReader {
+        data: &data,
+        position: 8,
+    };
+    let profile = reader.bits(8)?;
+    reader.bits(16)?; // constraint flags and level
+    reader.ue()?; // sps id
+    let mut chroma_format = 1;
+    let mut separate_colour_plane = 0;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+        chroma_format = reader.ue()?;
+        if chroma_format == 3 {
+            separate_colour_plane = reader.bit()?;
+        }
+        reader.ue()?; // bit depth luma
+        reader.ue()?; // bit depth chroma
+        reader.bit()?; // transform bypass
+        if reader.bit()? == 1 {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+            for list in 0..lists {
+                if reader.bit()? == 1 {
+                    skip_scaling_list(&mut reader, if list < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    reader.ue()?; // log2 max frame num
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        }
+        1 => {
+            reader.bit()?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        }
+        _ => {}
+    }
+    reader.ue()?; // max ref frames
+    reader.bit()?; // gaps allowed
+    let width_in_mbs = reader.ue()?.checked_add(1)?;
+    let height_in_map_units = reader.ue()?.checked_add(1)?;
+    let frame_mbs_only = reader.bit()?;
+    if frame_mbs_only == 0 {
+        reader.bit()?;
+    }
+    reader.bit()?; // direct 8x8 inference
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if reader.bit()? == 1 {
+        crop_left = reader.ue()?;
+        crop_right = reader.ue()?;
+        crop_top = reader.ue()?;
+        crop_bottom = reader.ue()?;
+    }
+    let (crop_unit_x, crop_unit_y) = if chroma_format == 0 || separate_colour_plane == 1 {
+        (1, 2 - frame_mbs_only)
+    } else {
+        let sub_width = if chroma_format == 3 { 1 } else { 2 };
+        let sub_height = if chroma_format == 1 { 2 } else { 1 };
+        (sub_width, sub_height * (2 - frame_mbs_only))
+    };
+    // Cropping larger than the coded size is invalid
+    let width = width_in_mbs.checked_mul(16)?.checked_sub(
+        crop_left
+            .checked_add(crop_right)?
+            .checked_mul(crop_unit_x)?,
+    )?;
+    let height = height_in_map_units
+        .checked_mul(16 * (2 - frame_mbs_only))?
+        .checked_sub(
+            crop_top
+                .checked_add(crop_bottom)?
+                .checked_mul(crop_unit_y)?,
+        )?;
+    Some((u16::try_from(width).ok()?, u16::try_from(height).ok()?))
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let mut last = 8;
+    let mut next = 8;
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + reader.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+// MP4 boxes
+
+pub(crate) fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 8);
+    data.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+    data
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+    data.extend_from_slice(payload);
+    mp4_box(kind, &data)
+}
+
+fn ftyp() -> Vec<u8> {
+    mp4_box(b"ftyp", b"isom\x00\x00\x02\x00isomiso2avc1mp41")
+}
+
+// Timing of a track in its own timescale
+struct TrackTiming {
+    timescale: u32,
+    durations: Vec<u32>,
+    // Composition offsets of video samples
+    offsets: Vec<u32>,
+    // First presentation time in 90 kHz
+    start: i64,
+    // Media time of the first presented sample
+    media_time: i64,
+}
+
+impl TrackTiming {
+    fn duration(&self) -> u64 {
+        self.durations.iter().map(|d| *d as u64).sum()
+    }
+}
+
+// Duration of an AAC frame in 90 kHz
+fn frame_duration(sample_rate: u32) -> i64 {
+    AAC_FRAME_SAMPLES as i64 * VIDEO_TIMESCALE as i64 / sample_rate as i64
+}
+
+fn track_timing(track: &Track) -> TrackTiming {
+    match &track.codec {
+        Some(Codec::Aac { sample_rate, .. }) => {
+            // A frame lasts until the next one when timestamps leave a gap
+            let expected = frame_duration(*sample_rate);
+            let mut durations: Vec<u32> = track
+                .samples
+                .windows(2)
+                .map(|pair| {
+                    let delta = pair[1].pts - pair[0].pts;
+                    if delta > expected + expected / 2 {
+                        (delta * *sample_rate as i64 / VIDEO_TIMESCALE as i64) as u32
+                    } else {
+                        AAC_FRAME_SAMPLES
+                    }
+                })
+                .collect();
+            durations.push(AAC_FRAME_SAMPLES);
+            TrackTiming {
+                timescale: *sample_rate,
+                durations,
+                offsets: Vec::new(),
+                start: track.samples[0].pts,
+                media_time: 0,
+            }
+        }
+        _ => {
+            let samples = &track.samples;
+            let mut durations = Vec::with_capacity(samples.len());
+            let mut previous = DEFAULT_FRAME_DURATION;
+            for pair in samples.windows(2) {
+                let delta = pair[1].dts - pair[0].dts;
+                if delta > 0 {
+                    previous = delta;
+                }
+                durations.push(previous as u32);
+            }
+            durations.push(previous as u32);
+            let first_dts = samples[0].dts;
+            let start = samples.iter().map(|s| s.pts).min().unwrap_or(first_dts);
+            TrackTiming {
+                timescale: VIDEO_TIMESCALE,
+                durations,
+                offsets: samples.iter().map(|s| (s.pts - s.dts) as u32).collect(),
+                start,
+                media_time: start - first_dts,
+            }
+        }
+    }
+}
+
+fn moov(tracks: &[Track]) -> Vec<u8> {
+    let timings: Vec<TrackTiming> = tracks.iter().map(track_timing).collect();
+    let movie_start = timings.iter().map(|t| t.start).min().unwrap_or(0);
+    let mut traks = Vec::new();
+    let mut movie_duration = 0;
+    for (index, (track, timing)) in tracks.iter().zip(timings.iter()).enumerate() {
+        let delay =
+            ((timing.start - movie_start) * MOVIE_TIMESCALE as i64 / VIDEO_TIMESCALE as i64) as u64;
+        let media_duration = timing.duration() * MOVIE_TIMESCALE as u64 / timing.timescale as u64;
+        movie_duration = movie_duration.max(delay + media_duration);
+        traks.extend(trak(track, timing, index as u32 + 1, delay, media_duration));
+    }
+    let mut mvhd = Vec::new();
+    mvhd.extend_from_slice(&[0; 16]); // creation and modification time
+    mvhd.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+    mvhd.extend_from_slice(&movie_duration.to_be_bytes());
+    mvhd.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+    mvhd.extend_from_slice(&[0; 10]);
+    MATRIX
+        .iter()
+        .for_each(|v| mvhd.extend_from_slice(&v.to_be_bytes()));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&(tracks.len() as u32 + 1).to_be_bytes());
+    let mut payload = full_box(b"mvhd", 1, 0, &mvhd);
+    payload.extend(traks);
+    mp4_box(b"moov", &payload)
+}
+
+fn trak(
+    track: &Track,
+    timing: &TrackTiming,
+    track_id: u32,
+    delay: u64,
+    media_duration: u64,
+) -> Vec<u8> {
+    let (width, height) = match &track.codec {
+        Some(Codec::H264 { width, height, .. }) => (*width as u32, *height as u32),
+        _ => (0, 0),
+    };
+    let mut tkhd = Vec::new();
+    tkhd.extend_from_slice(&[0; 16]);
+    tkhd.extend_from_slice(&track_id.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&(delay + media_duration).to_be_bytes());
+    tkhd.extend_from_slice(&[0; 8]);
+    tkhd.extend_from_slice(&[0; 4]); // layer and alternate group
+    let volume: u16 = if track.video { 0 } else { 0x0100 };
+    tkhd.extend_from_slice(&volume.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 2]);
+    MATRIX
+        .iter()
+        .for_each(|v| tkhd.extend_from_slice(&v.to_be_bytes()));
+    tkhd.extend_from_slice(&(width << 16).to_be_bytes());
+    tkhd.extend_from_slice(&(height << 16).to_be_bytes());
+
+    // Empty edit delays a track which starts later than the movie
+    let mut entries = Vec::new();
+    let mut entry_count = 0u32;
+    if delay > 0 {
+        entries.extend_from_slice(&delay.to_be_bytes());
+        entries.extend_from_slice(&(-1i64).to_be_bytes());
+        entries.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        entry_count += 1;
+    }
+    entries.extend_from_slice(&media_duration.to_be_bytes());
+    entries.extend_from_slice(&timing.media_time.to_be_bytes());
+    entries.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+    entry_count += 1;
+    let mut elst = entry_count.to_be_bytes().to_vec();
+    elst.extend(entries);
+    let edts = mp4_box(b"edts", &full_box(b"elst", 1, 0, &elst));
+
+    let mut mdhd = Vec::new();
+    mdhd.extend_from_slice(&[0; 16]);
+    mdhd.extend_from_slice(&timing.timescale.to_be_bytes());
+    mdhd.extend_from_slice(&timing.duration().to_be_bytes());
+    mdhd.extend_from_slice(&0x55c4u16.to_be_bytes()); // und
+    mdhd.extend_from_slice(&[0; 2]);
+    let (handler, name, media_header) = if track.video {
+        (b"vide", "VideoHandler", full_box(b"vmhd", 0, 1, &[0; 8]))
+    } else {
+        (b"soun", "SoundHandler", full_box(b"smhd", 0, 0, &[0; 4]))
+    };
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(handler);
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(name.as_bytes());
+    hdlr.push(0);
+    let dref = full_box(
+        b"dref",
+        0,
+        0,
+        &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat(),
+    );
+    let minf = mp4_box(
+        b"minf",
+        &[media_header, mp4_box(b"dinf", &dref), stbl(track, timing)].concat(),
+    );
+    let mdia = mp4_box(
+        b"mdia",
+        &[
+            full_box(b"mdhd", 1, 0, &mdhd),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            minf,
+        ]
+        .concat(),
+    );
+    mp4_box(
+        b"trak",
+        &[full_box(b"tkhd", 1, 0x3, &tkhd), edts, mdia].concat(),
+    )
+}
+
+fn stbl(track: &Track, timing: &TrackTiming) -> Vec<u8> {
+    let mut boxes = full_box(
+        b"stsd",
+        0,
+        0,
+        &[&1u32.to_be_bytes()[..], &sample_entry(track)].concat(),
+    );
+    boxes.extend(full_box(b"stts", 0, 0, &run_length(&timing.durations)));
+    if timing.offsets.iter().any(|o| *o != 0) {
+        boxes.extend(full_box(b"ctts", 0, 0, &run_length(&timing.offsets)));
+    }
+    if track.video {
+        let sync: Vec<u32> = track
+            .samples
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.sync)
+            .map(|(i, _)| i as u32 + 1)
+            .collect();
+        let mut stss = (sync.len() as u32).to_be_bytes().to_vec();
+        sync.iter()
+            .for_each(|s| stss.extend_from_slice(&s.to_be_bytes()));
+        boxes.extend(full_box(b"stss", 0, 0, &stss));
+    }
+
+    // Chunks with the same sample count share one stsc entry
+    let mut stsc = Vec::new();
+    let mut stsc_count = 0u32;
+    let mut previous_count = None;
+    for (index, (_, count)) in track.chunks.iter().enumerate() {
+        if previous_count != Some(*count) {
+            stsc.extend_from_slice(&(index as u32 + 1).to_be_bytes());
+            stsc.extend_from_slice(&count.to_be_bytes());
+            stsc.extend_from_slice(&1u32.to_be_bytes());
+            stsc_count += 1;
+            previous_count = Some(*count);
+        }
+    }
+    boxes.extend(full_box(
+        b"stsc",
+        0,
+        0,
+        &[&stsc_count.to_be_bytes()[..], &stsc].concat(),
+    ));
+
+    let mut stsz = vec![0; 4];
+    stsz.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+    track
+        .samples
+        .iter()
+        .for_each(|s| stsz.extend_from_slice(&s.size.to_be_bytes()));
+    boxes.extend(full_box(b"stsz", 0, 0, &stsz));
+
+    let mut co64 = (track.chunks.len() as u32).to_be_bytes().to_vec();
+    track
+        .chunks
+        .iter()
+        .for_each(|(offset, _)| co64.extend_from_slice(&offset.to_be_bytes()));
+    boxes.extend(full_box(b"co64", 0, 0, &co64));
+    mp4_box(b"stbl", &boxes)
+}
+
+// Entries of sample count and value
+fn run_length(values: &[u32]) -> Vec<u8> {
+    let mut entries: Vec<(u32, u32)> = Vec::new();
+    for value in values {
+        match entries.last_mut() {
+            Some((count, last)) if last == value => *count += 1,
+            _ => entries.push((1, *value)),
+        }
+    }
+    let mut data = (entries.len() as u32).to_be_bytes().to_vec();
+    for (count, value) in entries {
+        data.extend_from_slice(&count.to_be_bytes());
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    data
+}
+
+fn sample_entry(track: &Track) -> Vec<u8> {
+    // Reserved bytes and data reference index
+    let mut entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+    match &track.codec {
+        Some(Codec::H264 {
+            sps,
+            pps,
+            profile,
+            width,
+            height,
+        }) => {
+            entry.extend_from_slice(&[0; 16]);
+            entry.extend_from_slice(&width.to_be_bytes());
+            entry.extend_from_slice(&height.to_be_bytes());
+            entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&1u16.to_be_bytes()); // frame count
+            entry.extend_from_slice(&[0; 32]); // compressor name
+            entry.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+            entry.extend_from_slice(&0xffffu16.to_be_bytes());
+            let mut avcc = vec![1, profile[0], profile[1], profile[2], 0xff, 0xe1];
+            avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            avcc.extend_from_slice(sps);
+            avcc.push(1);
+            avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            avcc.extend_from_slice(pps);
+            entry.extend(mp4_box(b"avcC", &avcc));
+            mp4_box(b"avc1", &entry)
+        }
+        Some(Codec::Aac {
+            config,
+            sample_rate,
+            channels,
+        }) => {
+            entry.extend_from_slice(&[0; 8]);
+            entry.extend_from_slice(&channels.to_be_bytes());
+            entry.extend_from_slice(&16u16.to_be_bytes()); // sample size
+            entry.extend_from_slice(&[0; 4]);
+            entry.extend_from_slice(&((sample_rate & 0xffff) << 16).to_be_bytes());
+            // ES descriptor with decoder config and SL config
+            let decoder_specific = [&[0x05, 2][..], config].concat();
+            let mut decoder_config = vec![0x04, 13 + decoder_specific.len() as u8, 0x40, 0x15];
+            decoder_config.extend_from_slice(&[0; 11]);
+            decoder_config.extend(decoder_specific);
+            let mut es = vec![0x03, 3 + decoder_config.len() as u8 + 3, 0, 0, 0];
+            es.extend(decoder_config);
+            es.extend_from_slice(&[0x06, 1, 0x02]);
+            entry.extend(full_box(b"esds", 0, 0, &es));
+            mp4_box(b"mp4a", &entry)
+        }
+        None => Vec::new(),
+    }
+}
@@ -0,0 +1,756 @@
+use crate::crypto::KEY_SIZE;
+use crate::ts::{
+    crc32_mpeg2, is_transport_stream, nal_units, packet_payload_offset, packet_pid, parse_pat,
+    psi_section, remove_emulation_prevention, STREAM_TYPE_AAC, STREAM_TYPE_H264, TS_PACKET_SIZE,
+    TS_SYNC_BYTE,
+};
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit};
+use aes::Aes128;
+use saidl_helper::error::{Error, Result};
+use std::collections::HashMap;
+
+const BLOCK_SIZE: usize = 16;
+
+// SAMPLE-AES stream types
+const STREAM_TYPE_SAMPLE_AES_H264: u8 = 0xdb;
+const STREAM_TYPE_SAMPLE_AES_AAC: u8 = 0xcf;
+const STREAM_TYPE_SAMPLE_AES_AC3: u8 = 0xc1;
+
+// Video of fMP4 cbcs encrypts 1 block then skips 9 blocks when the track has no tenc pattern
+const CBCS_CRYPT_BLOCKS: usize = 1;
+const CBCS_SKIP_BLOCKS: usize = 9;
+
+// Crypt and skip blocks of the cbcs pattern by track id
+pub type CbcsPatterns = HashMap<u32, (usize, usize)>;
+
+pub fn decrypt_sample_aes(
+    data: &[u8],
+    key: &[u8; KEY_SIZE],
+    iv: &[u8; KEY_SIZE],
+    patterns: &CbcsPatterns,
+) -> Result<Vec<u8>> {
+    if is_transport_stream(data) {
+        decrypt_transport_stream(data, key, iv)
+    } else {
+        decrypt_fmp4(data, key, iv, patterns)
+    }
+}
+
+// CBC decryption which can skip clear blocks while keeping the chain
+struct CbcChain {
+    cipher: Aes128,
+    iv: [u8; BLOCK_SIZE],
+    previous: [u8; BLOCK_SIZE],
+}
+
+impl CbcChain {
+    fn new(key: &[u8; KEY_SIZE], iv: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: Aes128::new(GenericArray::from_slice(key)),
+            iv: *iv,
+            previous: *iv,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.previous = self.iv;
+    }
+
+    fn decrypt_block(&mut self, block: &mut [u8]) {
+        let mut cipher_block = [0u8; BLOCK_SIZE];
+        cipher_block.copy_from_slice(block);
+        self.cipher
+            .decrypt_block(GenericArray::from_mut_slice(block));
+        for (byte, prev) in block.iter_mut().zip(self.previous.iter()) {
+            *byte ^= prev;
+        }
+        self.previous = cipher_block;
+    }
+}
+
+// MPEG-TS
+
+#[derive(Clone, Copy)]
+enum EncryptedStream {
+    H264,
+    Aac,
+}
+
+struct PesBuffer {
+    stream: EncryptedStream,
+    packets: Vec<usize>,
+    data: Vec<u8>,
+}
+
+fn decrypt_transport_stream(
+    data: &[u8],
+    key: &[u8; KEY_SIZE],
+    iv: &[u8; KEY_SIZE],
+) -> Result<Vec<u8>> {
+    let mut packets: Vec<[u8; TS_PACKET_SIZE]> = data
+        .chunks_exact(TS_PACKET_SIZE)
+        .map(|c| c.try_into().expect("Chunk size is checked"))
+        .collect();
+    let mut chain = CbcChain::new(key, iv);
+    let mut pmt_pids = Vec::new();
+    let mut streams: HashMap<u16, EncryptedStream> = HashMap::new();
+    let mut buffers: HashMap<u16, PesBuffer> = HashMap::new();
+    // Rewritten packets by original packet index
+    let mut replacements: HashMap<usize, Vec<[u8; TS_PACKET_SIZE]>> = HashMap::new();
+
+    for index in 0..packets.len() {
+        let packet = &packets[index];
+        let pid = packet_pid(packet);
+        let start = packet[1] & 0x40 != 0;
+        let payload = match packet_payload_offset(packet) {
+            Some(offset) => offset,
+            None => continue,
+        };
+        if pid == 0 && start {
+            pmt_pids = parse_pat(&packet[payload..]);
+            continue;
+        }
+        if pmt_pids.contains(&pid) && start {
+            streams.extend(rewrite_pmt(&mut packets[index], payload)?);
+            continue;
+        }
+        let stream = match streams.get(&pid) {
+            Some(s) => *s,
+            None => continue,
+        };
+        if start {
+            if let Some(buffer) = buffers.remove(&pid) {
+                flush_pes(buffer, &packets, &mut chain, &mut replacements);
+            }
+            buffers.insert(
+                pid,
+                PesBuffer {
+                    stream,
+                    packets: Vec::new(),
+                    data: Vec::new(),
+                },
+            );
+        }
+        if let Some(buffer) = buffers.get_mut(&pid) {
+            buffer.packets.push(index);
+            buffer.data.extend_from_slice(&packets[index][payload..]);
+        }
+    }
+    for (_, buffer) in buffers.drain() {
+        flush_pes(buffer, &packets, &mut chain, &mut replacements);
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut continuity: HashMap<u16, u8> = HashMap::new();
+    for (index, packet) in packets.iter().enumerate() {
+        let rewritten = match replacements.get(&index) {
+            Some(p) => p.clone(),
+            None => vec![*packet],
+        };
+        for mut packet in rewritten {
+            let pid = packet_pid(&packet);
+            if streams.contains_key(&pid) && packet[3] & 0x10 != 0 {
+                // Packet count may change, so renumber continuity counters
+                let counter = continuity.entry(pid).or_insert(packet[3] & 0x0f);
+                packet[3] = (packet[3] & 0xf0) | *counter;
+                *counter = (*counter + 1) & 0x0f;
+            }
+            output.extend_from_slice(&packet);
+        }
+    }
+    Ok(output)
+}
+
+// Restore clear stream types in PMT and return encrypted elementary streams
+fn rewrite_pmt(
+    packet: &mut [u8; TS_PACKET_SIZE],
+    payload: usize,
+) -> Result<Vec<(u16, EncryptedStream)>> {
+    let section_start = payload + 1 + packet[payload] as usize;
+    let section_length = match psi_section(&packet[payload..]) {
+        Some(s) => s.len(),
+        None => {
+            return Err(Error::Parse(
+                "Invalid PMT in SAMPLE-AES segment".to_string(),
+            ));
+        }
+    };
+    let section = &mut packet[section_start..section_start + section_length];
+    let program_info_length = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+    let mut streams = Vec::new();
+    let mut position = 12 + program_info_length;
+    while position + 5 <= section_length - 4 {
+        let pid = (((section[position + 1] & 0x1f) as u16) << 8) | section[position + 2] as u16;
+        let encrypted = match section[position] {
+            STREAM_TYPE_SAMPLE_AES_H264 => Some((STREAM_TYPE_H264, EncryptedStream::H264)),
+            STREAM_TYPE_SAMPLE_AES_AAC => Some((STREAM_TYPE_AAC, EncryptedStream::Aac)),
+            STREAM_TYPE_SAMPLE_AES_AC3 => {
+                println!(
+                    "SAMPLE-AES AC-3 audio is not supported, stream {} is kept",
+                    pid
+                );
+                None
+            }
+            _ => None,
+        };
+        if let Some((clear_type, stream)) = encrypted {
+            section[position] = clear_type;
+            streams.push((pid, stream));
+        }
+        let es_info_length =
+            (((section[position + 3] & 0x0f) as usize) << 8) | section[position + 4] as usize;
+        position += 5 + es_info_length;
+    }
+    let crc = crc32_mpeg2(&section[..section_length - 4]);
+    section[section_length - 4..].copy_from_slice(&crc.to_be_bytes());
+    Ok(streams)
+}
+
+fn flush_pes(
+    buffer: PesBuffer,
+    packets: &[[u8; TS_PACKET_SIZE]],
+    chain: &mut CbcChain,
+    replacements: &mut HashMap<usize, Vec<[u8; TS_PACKET_SIZE]>>,
+) {
+    let PesBuffer {
+        stream,
+        packets: indexes,
+        mut data,
+    } = buffer;
+    // PES header: start code, stream id, length, flags and optional fields
+    if data.len() < 9 || data[..3] != [0, 0, 1] {
+        return;
+    }
+    let header_length = 9 + data[8] as usize;
+    if header_length > data.len() {
+        return;
+    }
+    let decrypted = match stream {
+        EncryptedStream::H264 => decrypt_h264(&data[header_length..], chain),
+        EncryptedStream::Aac => decrypt_adts(&data[header_length..], chain),
+    };
+    data.truncate(header_length);
+    data.extend_from_slice(&decrypted);
+    let pes_length = u16::from_be_bytes([data[4], data[5]]);
+    if pes_length != 0 {
+        let pes_length = (data.len() - 6).min(u16::MAX as usize) as u16;
+        data[4..6].copy_from_slice(&pes_length.to_be_bytes());
+    }
+
+    let mut rewritten = packetize(&data, &indexes, packets);
+    // Keep packets in place, extra packets follow the last one
+    let extra = rewritten.split_off(indexes.len());
+    for (index, packet) in indexes.iter().zip(rewritten) {
+        replacements.insert(*index, vec![packet]);
+    }
+    let last = indexes.last().expect("PES has at least one packet");
+    if let Some(packets) = replacements.get_mut(last) {
+        packets.extend(extra);
+    }
+}
+
+// Spread PES data over packets, reusing headers and adaptation fields of the original
+// packets, so the result has at least as many packets as the original PES
+fn packetize(
+    data: &[u8],
+    indexes: &[usize],
+    packets: &[[u8; TS_PACKET_SIZE]],
+) -> Vec<[u8; TS_PACKET_SIZE]> {
+    let mut result = Vec::new();
+    let mut position = 0;
+    let template = packets[indexes[0]];
+    let mut slot = 0;
+    while position < data.len() || slot < indexes.len() {
+        let original = match indexes.get(slot) {
+            Some(i) => packets[*i],
+            None => continuation_header(&template),
+        };
+        slot += 1;
+        let adaptation = if original[3] & 0x20 != 0 {
+            original[4..5 + original[4] as usize].to_vec()
+        } else {
+            Vec::new()
+        };
+        let capacity = TS_PACKET_SIZE - 4 - adaptation.len();
+        let size = capacity.min(data.len() - position);
+        let mut packet = [0xffu8; TS_PACKET_SIZE];
+        packet[..4].copy_from_slice(&original[..4]);
+        write_payload(&mut packet, &adaptation, &data[position..position + size]);
+        position += size;
+        result.push(packet);
+    }
+    result
+}
+
+fn continuation_header(template: &[u8; TS_PACKET_SIZE]) -> [u8; TS_PACKET_SIZE] {
+    let mut packet = [0xffu8; TS_PACKET_SIZE];
+    packet[0] = TS_SYNC_BYTE;
+    // Clear payload unit start indicator
+    packet[1] = template[1] & 0x1f;
+    packet[2] = template[2];
+    packet[3] = 0x10;
+    packet
+}
+
+fn write_payload(packet: &mut [u8; TS_PACKET_SIZE], adaptation: &[u8], payload: &[u8]) {
+    let available = TS_PACKET_SIZE - 4 - payload.len();
+    let mut adaptation = adaptation.to_vec();
+    if adaptation.len() < available {
+        // Fill the gap with adaptation field stuffing
+        if adaptation.is_empty() {
+            adaptation.push(0);
+        }
+        if adaptation.len() == 1 && available > 1 {
+            // Empty flags
+            adaptation.push(0);
+        }
+        adaptation.resize(available, 0xff);
+        adaptation[0] = (available - 1) as u8;
+    }
+    let mut control = 0x00;
+    if !adaptation.is_empty() {
+        control |= 0x20;
+    }
+    if !payload.is_empty() {
+        control |= 0x10;
+    }
+    packet[3] = (packet[3] & 0x0f) | control;
+    packet[4..4 + adaptation.len()].copy_from_slice(&adaptation);
+    packet[4 + adaptation.len()..].copy_from_slice(payload);
+}
+
+// H.264 NAL units of type 1 and 5 longer than 48 bytes: 32 clear bytes,
+// then one encrypted block every 10 blocks, the chain restarts for each NAL
+fn decrypt_h264(data: &[u8], chain: &mut CbcChain) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut copied = 0;
+    for (start, end) in nal_units(data) {
+        let nal = &data[start..end];
+        let nal_type = nal[0] & 0x1f;
+        if nal.len() <= 48 || (nal_type != 1 && nal_type != 5) {
+            continue;
+        }
+        let mut unescaped = remove_emulation_prevention(nal);
+        chain.reset();
+        let mut position = 32;
+        while position + BLOCK_SIZE < unescaped.len() {
+            chain.decrypt_block(&mut unescaped[position..position + BLOCK_SIZE]);
+            position += BLOCK_SIZE * 10;
+        }
+        output.extend_from_slice(&data[copied..start]);
+        output.extend_from_slice(&add_emulation_prevention(&unescaped));
+        copied = end;
+    }
+    output.extend_from_slice(&data[copied..]);
+    output
+}
+
+fn add_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + data.len() / 64);
+    let mut zeros = 0;
+    for byte in data {
+        if zeros >= 2 && *byte <= 3 {
+            output.push(3);
+            zeros = 0;
+        }
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+        output.push(*byte);
+    }
+    output
+}
+
+// ADTS frames: header and 16 clear bytes, then all whole blocks are encrypted
+fn decrypt_adts(data: &[u8], chain: &mut CbcChain) -> Vec<u8> {
+    let mut output = data.to_vec();
+    let mut position = 0;
+    while position + 7 <= output.len() {
+        let header = &output[position..];
+        if header[0] != 0xff || header[1] & 0xf0 != 0xf0 {
+            break;
+        }
+        let header_length = if header[1] & 0x01 == 0 { 9 } else { 7 };
+        let frame_length = (((header[3] & 0x03) as usize) << 11)
+            | ((header[4] as usize) << 3)
+            | ((header[5] as usize) >> 5);
+        if frame_length < header_length || position + frame_length > output.len() {
+            break;
+        }
+        let frame = &mut output[position + header_length..position + frame_length];
+        let encrypted_end = frame.len() - frame.len() % BLOCK_SIZE;
+        chain.reset();
+        let mut block = BLOCK_SIZE;
+        while block < encrypted_end {
+            chain.decrypt_block(&mut frame[block..block + BLOCK_SIZE]);
+            block += BLOCK_SIZE;
+        }
+        position += frame_length;
+    }
+    output
+}
+
+// fMP4 with cbcs scheme
+
+struct Mp4Box {
+    kind: [u8; 4],
+    start: usize,
+    payload: usize,
+    end: usize,
+}
+
+fn child_boxes(data: &[u8], start: usize, end: usize) -> Vec<Mp4Box> {
+    let mut boxes = Vec::new();
+    let mut position = start;
+    while position + 8 <= end {
+        let size = u32::from_be_bytes(data[position..position + 4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = data[position + 4..position + 8].try_into().unwrap();
+        let (size, header) = match size {
+            0 => (end - position, 8),
+            1 if position + 16 <= end => {
+                let size =
+                    u64::from_be_bytes(data[position + 8..position + 16].try_into().unwrap());
+                match usize::try_from(size) {
+                    Ok(size) => (size, 16),
+                    Err(_) => break,
+                }
+            }
+            _ => (size, 8),
+        };
+        // A size past the parent box ends the list
+        let box_end = match position.checked_add(size) {
+            Some(box_end) if size >= header && box_end <= end => box_end,
+            _ => break,
+        };
+        boxes.push(Mp4Box {
+            kind,
+            start: position,
+            payload: position + header,
+            end: box_end,
+        });
+        position = box_end;
+    }
+    boxes
+}
+
+fn read_u32(data: &[u8], position: &mut usize) -> Option<u32> {
+    let value = u32::from_be_bytes(data.get(*position..*position + 4)?.try_into().ok()?);
+    *position += 4;
+    Some(value)
+}
+
+fn read_u16(data: &[u8], position: &mut usize) -> Option<u16> {
+    let value = u16::from_be_bytes(data.get(*position..*position + 2)?.try_into().ok()?);
+    *position += 2;
+    Some(value)
+}
+
+struct SampleEncryption {
+    iv: Option<[u8; KEY_SIZE]>,
+    // Pairs of clear and protected byte counts
+    subsamples: Vec<(usize, usize)>,
+}
+
+fn decrypt_fmp4(
+    data: &[u8],
+    key: &[u8; KEY_SIZE],
+    iv: &[u8; KEY_SIZE],
+    patterns: &CbcsPatterns,
+) -> Result<Vec<u8>> {
+    let mut output = data.to_vec();
+    let invalid = |kind: &str| Error::Parse(format!("Invalid {} box in SAMPLE-AES segment", kind));
+    let moofs: Vec<Mp4Box> = child_boxes(data, 0, data.len())
+        .into_iter()
+        .filter(|b| &b.kind == b"moof")
+        .collect();
+    if moofs.is_empty() {
+        return Err(Error::Parse(
+            "SAMPLE-AES segment is neither MPEG-TS nor fragmented MP4".to_string(),
+        ));
+    }
+    for moof in moofs {
+        for traf in child_boxes(data, moof.payload, moof.end) {
+            if &traf.kind != b"traf" {
+                continue;
+            }
+            let children = child_boxes(data, traf.payload, traf.end);
+            let find = |kind: &[u8; 4]| children.iter().find(|b| &b.kind == kind);
+            let tfhd = find(b"tfhd").ok_or_else(|| invalid("tfhd"))?;
+            let (track_id, base_offset, default_size) =
+                parse_tfhd(data, tfhd, moof.start).ok_or_else(|| invalid("tfhd"))?;
+            let senc = match find(b"senc") {
+                Some(b) => parse_senc(data, b).ok_or_else(|| invalid("senc"))?,
+                // Track is not encrypted
+                None => continue,
+            };
+            let mut samples = Vec::new();
+            for trun in children.iter().filter(|b| &b.kind == b"trun") {
+                samples.extend(
+                    parse_trun(data, trun, base_offset, default_size)
+                        .ok_or_else(|| invalid("trun"))?,
+                );
+            }
+            for ((offset, size), encryption) in samples.into_iter().zip(senc) {
+                let sample = match offset.checked_add(size) {
+                    Some(end) if end <= output.len() => &mut output[offset..end],
+                    _ => return Err(invalid("mdat")),
+                };
+                decrypt_cbcs_sample(sample, &encryption, key, iv, patterns.get(&track_id));
+            }
+        }
+    }
+    Ok(output)
+}
+
+// Return track id, base data offset and default sample size
+fn parse_tfhd(
+    data: &[u8],
+    tfhd: &Mp4Box,
+    moof_start: usize,
+) -> Option<(u32, usize, Option<usize>)> {
+    let mut position = tfhd.payload;
+    let flags = read_u32(data, &mut position)? & 0x00ff_ffff;
+    let track_id = read_u32(data, &mut position)?;
+    let mut base_offset = moof_start;
+    if flags & 0x01 != 0 {
+        let high = read_u32(data, &mut position)? as u64;
+        let low = read_u32(data, &mut position)? as u64;
+        base_offset = usize::try_from((high << 32) | low).ok()?;
+    }
+    if flags & 0x02 != 0 {
+        position += 4;
+    }
+    if flags & 0x08 != 0 {
+        position += 4;
+    }
+    let default_size = if flags & 0x10 != 0 {
+        Some(read_u32(data, &mut position)? as usize)
+    } else {
+        None
+    };
+    Some((track_id, base_offset, default_size))
+}
+
+// Return absolute offset and size of each sample
+fn parse_trun(
+    data: &[u8],
+    trun: &Mp4Box,
+    base_offset: usize,
+    default_size: Option<usize>,
+) -> Option<Vec<(usize, usize)>> {
+    let mut position = trun.payload;
+    let flags = read_u32(data, &mut position)? & 0x00ff_ffff;
+    let count = read_u32(data, &mut position)?;
+    let mut offset = base_offset;
+    if flags & 0x001 != 0 {
+        // Signed offset, it cannot point before the start of the segment
+        let data_offset = read_u32(data, &mut position)? as i32;
+        offset = usize::try_from(
+            i64::try_from(base_offset)
+                .ok()?
+                .checked_add(data_offset as i64)?,
+        )
+        .ok()?;
+    }
+    if flags & 0x004 != 0 {
+        position += 4;
+    }
+    let mut samples = Vec::new();
+    for _ in 0..count {
+        if flags & 0x100 != 0 {
+            position += 4;
+        }
+        let size = if flags & 0x200 != 0 {
+            read_u32(data, &mut position)? as usize
+        } else {
+            default_size?
+        };
+        if flags & 0x400 != 0 {
+            position += 4;
+        }
+        if flags & 0x800 != 0 {
+            position += 4;
+        }
+        samples.push((offset, size));
+        offset = offset.checked_add(size)?;
+    }
+    Some(samples)
+}
+
+// Per sample IV size is declared in the init segment, so try each valid size
+// and keep the one which matches the box length exactly
+fn parse_senc(data: &[u8], senc: &Mp4Box) -> Option<Vec<SampleEncryption>> {
+    [0, 8, 16]
+        .iter()
+        .find_map(|iv_size| parse_senc_with_iv_size(data, senc, *iv_size))
+}
+
+fn parse_senc_with_iv_size(
+    data: &[u8],
+    senc: &Mp4Box,
+    iv_size: usize,
+) -> Option<Vec<SampleEncryption>> {
+    let mut position = senc.payload;
+    let flags = read_u32(data, &mut position)? & 0x00ff_ffff;
+    let count = read_u32(data, &mut position)?;
+    let mut samples = Vec::new();
+    for _ in 0..count {
+        let iv = if iv_size > 0 {
+            let mut iv = [0u8; KEY_SIZE];
+            iv[..iv_size].copy_from_slice(data.get(position..position + iv_size)?);
+            position += iv_size;
+            Some(iv)
+        } else {
+            None
+        };
+        let mut subsamples = Vec::new();
+        if flags & 0x2 != 0 {
+            let subsample_count = read_u16(data, &mut position)?;
+            for _ in 0..subsample_count {
+                let clear = read_u16(data, &mut position)? as usize;
+                let protected = read_u32(data, &mut position)? as usize;
+                subsamples.push((clear, protected));
+            }
+        }
+        if position > senc.end {
+            return None;
+        }
+        samples.push(SampleEncryption { iv, subsamples });
+    }
+    if position == senc.end {
+        Some(samples)
+    } else {
+        None
+    }
+}
+
+// Samples use the pattern of their track, without it samples with subsamples (video)
+// use the 1:9 pattern and whole samples (audio) are fully encrypted,
+// the chain restarts at each protected range
+fn decrypt_cbcs_sample(
+    sample: &mut [u8],
+    encryption: &SampleEncryption,
+    key: &[u8; KEY_SIZE],
+    iv: &[u8; KEY_SIZE],
+    pattern: Option<&(usize, usize)>,
+) {
+    let mut chain = CbcChain::new(key, encryption.iv.as_ref().unwrap_or(iv));
+    if encryption.subsamples.is_empty() {
+        let (crypt, skip) = pattern.copied().unwrap_or((1, 0));
+        decrypt_pattern(sample, &mut chain, crypt, skip);
+        return;
+    }
+    let (crypt, skip) = pattern
+        .copied()
+        .unwrap_or((CBCS_CRYPT_BLOCKS, CBCS_SKIP_BLOCKS));
+    let mut position = 0;
+    for (clear, protected) in &encryption.subsamples {
+        position += clear;
+        let end = (position + protected).min(sample.len());
+        if position >= end {
+            break;
+        }
+        chain.reset();
+        decrypt_pattern(&mut sample[position..end], &mut chain, crypt, skip);
+        position = end;
+    }
+}
+
+fn decrypt_pattern(data: &mut [u8], chain: &mut CbcChain, crypt: usize, skip: usize) {
+    let mut position = 0;
+    while position + BLOCK_SIZE <= data.len() {
+        for _ in 0..crypt {
+            if position + BLOCK_SIZE > data.len() {
+                return;
+            }
+            chain.decrypt_block(&mut data[position..position + BLOCK_SIZE]);
+            position += BLOCK_SIZE;
+        }
+        position += skip * BLOCK_SIZE;
+    }
+}
+
+// Pattern of each track from the tenc boxes of an initialization section,
+// tracks with a version 0 tenc have no pattern
+pub fn parse_cbcs_patterns(init: &[u8]) -> Result<CbcsPatterns> {
+    let mut patterns = HashMap::new();
+    let find = |parent: &Mp4Box, kind: &[u8; 4]| {
+        child_boxes(init, parent.payload, parent.end)
+            .into_iter()
+            .find(|b| &b.kind == kind)
+    };
+    for moov in child_boxes(init, 0, init.len()) {
+        if &moov.kind != b"moov" {
+            continue;
+        }
+        for trak in child_boxes(init, moov.payload, moov.end) {
+            if &trak.kind != b"trak" {
+                continue;
+            }
+            let track_id = match find(&trak, b"tkhd").and_then(|tkhd| parse_tkhd(init, &tkhd)) {
+                Some(id) => id,
+                None => continue,
+            };
+            let stsd = find(&trak, b"mdia")
+                .and_then(|mdia| find(&mdia, b"minf"))
+                .and_then(|minf| find(&minf, b"stbl"))
+                .and_then(|stbl| find(&stbl, b"stsd"));
+            let tenc = stsd
+                .map(|stsd| sample_entries(init, &stsd))
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|entry| find(&entry, b"sinf"))
+                .filter_map(|sinf| find(&sinf, b"schi"))
+                .find_map(|schi| find(&schi, b"tenc"));
+            if let Some(pattern) = tenc.and_then(|tenc| parse_tenc_pattern(init, &tenc)) {
+                patterns.insert(track_id, pattern?);
+            }
+        }
+    }
+    Ok(patterns)
+}
+
+fn parse_tkhd(data: &[u8], tkhd: &Mp4Box) -> Option<u32> {
+    let mut position = tkhd.payload;
+    let version = *data.get(position)?;
+    // Creation and modification times are 64 bit in version 1
+    position += if version == 1 { 20 } else { 12 };
+    read_u32(data, &mut position)
+}
+
+// Encrypted sample entries with their child boxes after the fixed fields
+fn sample_entries(data: &[u8], stsd: &Mp4Box) -> Vec<Mp4Box> {
+    child_boxes(data, stsd.payload + 8, stsd.end)
+        .into_iter()
+        .filter_map(|entry| {
+            let fields = match &entry.kind {
+                b"encv" => 78,
+                b"enca" => match data.get(entry.payload + 8..entry.payload + 10)? {
+                    [0, 1] => 44,
+                    [0, 2] => 64,
+                    _ => 28,
+                },
+                _ => return None,
+            };
+            let payload = entry.payload + fields;
+            (payload <= entry.end).then_some(Mp4Box { payload, ..entry })
+        })
+        .collect()
+}
+
+// None for a version 0 tenc, a 0:0 pattern encrypts every block
+fn parse_tenc_pattern(data: &[u8], tenc: &Mp4Box) -> Option<Result<(usize, usize)>> {
+    let version = *data.get(tenc.payload)?;
+    let pattern = *data.get(tenc.payload + 5)?;
+    if version == 0 {
+        return None;
+    }
+    let crypt = (pattern >> 4) as usize;
+    let skip = (pattern & 0x0f) as usize;
+    Some(match (crypt, skip) {
+        (0, 0) => Ok((1, 0)),
+        (0, _) => Err(Error::Parse(format!(
+            "Invalid cbcs pattern {}:{} in SAMPLE-AES initialization section",
+            crypt, skip
+        ))),
+        _ => Ok((crypt, skip)),
+    })
+}
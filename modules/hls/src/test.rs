@@ -1,11 +1,1000 @@
 #[cfg(test)]
 mod tests {
-    use crate::get_response_bytes;
+    use crate::crypto::{decrypt_aes128, parse_hex_key, sequence_iv};
+    use crate::playlist::ByteRange;
+    use crate::playlist::{
+        har_media, is_master_playlist, parse_attributes, parse_link_list, parse_master_playlist,
+        parse_media_playlist, parse_resolution, KeyMethod, VariantFilter,
+    };
+    use crate::{get_response_bytes, slice_byte_range};
+    use bytes::Bytes;
+    use reqwest::Url;
+    use saidl_helper::http::HttpClient;
+    use saidl_helper::import::parse_har;
+    use saidl_helper::retry::RetryPolicy;
+    use saidl_helper::test_util::test_dir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
-    #[test]
-    fn send_request_work() {
+    #[tokio::test]
+    #[ignore = "requires network access"]
+    async fn send_request_work() {
         let url = "https://google.com";
-        let x = get_response_bytes(url, None);
+        let client = HttpClient::build(&None, false, None, None).unwrap();
+        let x = get_response_bytes(url, &client, None, RetryPolicy::default())
+            .await
+            .unwrap();
         assert_ne!(x.len(), 0);
     }
+
+    const MEDIA_PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:7
+# Comment line
+#EXTINF:9.009,First
+seg-7.ts
+#EXTINF:9.5,
+/abs/seg-8.ts
+#EXTINF:3.003
+https://cdn.example.com/seg-9.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn parse_media_playlist_work() {
+        let base = Url::parse("https://example.com/video/index.m3u8").unwrap();
+        let playlist = parse_media_playlist(MEDIA_PLAYLIST, Some(&base)).unwrap();
+        assert_eq!(playlist.target_duration, Some(10));
+        assert_eq!(playlist.media_sequence, 7);
+        assert!(playlist.end_list);
+        assert_eq!(
+            playlist.urls(),
+            vec![
+                "https://example.com/video/seg-7.ts",
+                "https://example.com/abs/seg-8.ts",
+                "https://cdn.example.com/seg-9.ts",
+            ]
+        );
+        let first = &playlist.segments[0];
+        assert_eq!(first.duration, 9.009);
+        assert_eq!(first.title.as_deref(), Some("First"));
+        assert_eq!(first.sequence, 7);
+        assert_eq!(playlist.segments[1].title, None);
+        assert_eq!(playlist.segments[2].sequence, 9);
+    }
+
+    #[test]
+    fn parse_media_playlist_without_base_keep_uri() {
+        let playlist = parse_media_playlist(MEDIA_PLAYLIST, None).unwrap();
+        assert_eq!(playlist.segments[0].uri, "seg-7.ts");
+    }
+
+    #[test]
+    fn parse_media_playlist_reject_invalid() {
+        assert!(parse_media_playlist("seg-1.ts\n", None).is_err());
+        assert!(parse_media_playlist("#EXTM3U\n#EXTINF:abc,\nseg.ts", None).is_err());
+    }
+
+    #[test]
+    fn parse_link_list_keep_http_lines() {
+        let lines = vec!["http://a/1.ts", "#comment", "http://a/2.ts"];
+        let playlist = parse_link_list(lines.into_iter().map(String::from));
+        assert_eq!(playlist.urls(), vec!["http://a/1.ts", "http://a/2.ts"]);
+    }
+
+    const MASTER_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS="avc1.4d401e,mp4a.40.2"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2800000,RESOLUTION=1280x720,CODECS="avc1.4d401f,mp4a.40.2"
+mid/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080,CODECS="hvc1.1.6.L120,mp4a.40.2"
+high/index.m3u8
+"#;
+
+    fn master_variants() -> Vec<crate::playlist::Variant> {
+        let base = Url::parse("https://example.com/master.m3u8").unwrap();
+        parse_master_playlist(MASTER_PLAYLIST, Some(&base)).unwrap()
+    }
+
+    #[test]
+    fn parse_master_playlist_work() {
+        assert!(is_master_playlist(MASTER_PLAYLIST));
+        assert!(!is_master_playlist(MEDIA_PLAYLIST));
+        let variants = master_variants();
+        assert_eq!(variants.len(), 3);
+        assert_eq!(variants[0].uri, "https://example.com/low/index.m3u8");
+        assert_eq!(variants[0].bandwidth, 800000);
+        assert_eq!(variants[0].resolution, Some((640, 360)));
+        assert_eq!(variants[0].codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+    }
+
+    #[test]
+    fn select_variant_work() {
+        let variants = master_variants();
+        let select = |filter: VariantFilter| filter.select(&variants).map(|v| v.bandwidth);
+        assert_eq!(select(VariantFilter::default()), Some(5000000));
+        let lowest = VariantFilter {
+            lowest_bandwidth: true,
+            ..Default::default()
+        };
+        assert_eq!(select(lowest), Some(800000));
+        let resolution = VariantFilter {
+            resolution: Some((1280, 720)),
+            ..Default::default()
+        };
+        assert_eq!(select(resolution), Some(2800000));
+        let max_height = VariantFilter {
+            max_height: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(select(max_height), Some(2800000));
+        let codec = VariantFilter {
+            codec: Some("hvc1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(select(codec), Some(5000000));
+        let no_match = VariantFilter {
+            max_height: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(select(no_match), None);
+    }
+
+    #[test]
+    fn parse_attributes_work() {
+        let attributes = parse_attributes(r#"METHOD=AES-128,URI="key?a=1,b=2",IV=0x01"#);
+        assert_eq!(attributes["METHOD"], "AES-128");
+        assert_eq!(attributes["URI"], "key?a=1,b=2");
+        assert_eq!(attributes["IV"], "0x01");
+    }
+
+    #[test]
+    fn parse_resolution_work() {
+        assert_eq!(parse_resolution("1280x720"), Ok((1280, 720)));
+        assert!(parse_resolution("720p").is_err());
+    }
+
+    const ENCRYPTED_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:3
+#EXTINF:4,
+clear.ts
+#EXT-X-KEY:METHOD=AES-128,URI="key1.bin"
+#EXTINF:4,
+enc-1.ts
+#EXT-X-KEY:METHOD=AES-128,URI="https://keys.example.com/key2",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:4,
+enc-2.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4,
+clear-2.ts
+"#;
+
+    #[test]
+    fn parse_key_rotation_work() {
+        let base = Url::parse("https://example.com/live/index.m3u8").unwrap();
+        let playlist = parse_media_playlist(ENCRYPTED_PLAYLIST, Some(&base)).unwrap();
+        let keys: Vec<_> = playlist.segments.iter().map(|s| s.key.clone()).collect();
+        assert_eq!(keys[0], None);
+        let first = keys[1].as_ref().unwrap();
+        assert_eq!(first.method, KeyMethod::Aes128);
+        assert_eq!(first.uri, "https://example.com/live/key1.bin");
+        assert_eq!(first.iv, None);
+        let second = keys[2].as_ref().unwrap();
+        assert_eq!(second.uri, "https://keys.example.com/key2");
+        assert_eq!(
+            second.iv,
+            Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
+        );
+        assert_eq!(keys[3], None);
+    }
+
+    #[test]
+    fn parse_unsupported_key_method_fail() {
+        let content = "#EXTM3U\n#EXT-X-KEY:METHOD=UNKNOWN,URI=\"k\"\n#EXTINF:1,\na.ts";
+        assert!(parse_media_playlist(content, None).is_err());
+    }
+
+    #[test]
+    fn decrypt_aes128_work() {
+        use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+        type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+        let key = [7u8; 16];
+        let iv = sequence_iv(3);
+        let plain = b"segment payload which is longer than a block".to_vec();
+        let encrypted =
+            Aes128CbcEnc::new(&key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(&plain);
+        assert_ne!(encrypted, plain);
+        assert_eq!(decrypt_aes128(&encrypted, &key, &iv).unwrap(), plain);
+        assert!(decrypt_aes128(&encrypted, &[8u8; 16], &iv).is_err());
+    }
+
+    #[test]
+    fn sequence_iv_work() {
+        let iv = sequence_iv(258);
+        assert_eq!(iv[..14], [0u8; 14]);
+        assert_eq!(iv[14..], [1, 2]);
+        assert_eq!(
+            parse_hex_key("0x000102030405060708090A0B0C0D0E0F").unwrap()[15],
+            15
+        );
+        assert!(parse_hex_key("0x0102").is_err());
+    }
+
+    // Fixtures for SAMPLE-AES, encrypted the way the packager does
+    mod sample_aes_fixture {
+        use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+        use aes::Aes128;
+
+        pub const KEY: [u8; 16] = [0x2b; 16];
+        pub const IV: [u8; 16] = [0x11; 16];
+        pub const VIDEO_PID: u16 = 0x100;
+        pub const AUDIO_PID: u16 = 0x101;
+
+        pub struct CbcEncryptor {
+            cipher: Aes128,
+            previous: [u8; 16],
+        }
+
+        impl CbcEncryptor {
+            pub fn new() -> Self {
+                Self {
+                    cipher: Aes128::new(GenericArray::from_slice(&KEY)),
+                    previous: IV,
+                }
+            }
+
+            pub fn encrypt_block(&mut self, block: &mut [u8]) {
+                for (byte, prev) in block.iter_mut().zip(self.previous.iter()) {
+                    *byte ^= prev;
+                }
+                self.cipher
+                    .encrypt_block(GenericArray::from_mut_slice(block));
+                self.previous.copy_from_slice(block);
+            }
+        }
+
+        pub fn add_emulation_prevention(data: &[u8]) -> Vec<u8> {
+            let mut output = Vec::new();
+            let mut zeros = 0;
+            for byte in data {
+                if zeros >= 2 && *byte <= 3 {
+                    output.push(3);
+                    zeros = 0;
+                }
+                zeros = if *byte == 0 { zeros + 1 } else { 0 };
+                output.push(*byte);
+            }
+            output
+        }
+
+        // Slice NAL unit with clear bytes at encrypted positions, so the
+        // escaped clear unit is longer than the escaped encrypted unit
+        pub fn nal_unit() -> Vec<u8> {
+            let mut nal = vec![0x65];
+            nal.extend((1..400).map(|i| (i % 251) as u8 | 1));
+            nal[32..48].fill(0);
+            nal[192..208].fill(0);
+            nal
+        }
+
+        pub fn encrypt_nal(nal: &[u8]) -> Vec<u8> {
+            let mut data = nal.to_vec();
+            let mut encryptor = CbcEncryptor::new();
+            let mut position = 32;
+            while position + 16 < data.len() {
+                encryptor.encrypt_block(&mut data[position..position + 16]);
+                position += 160;
+            }
+            data
+        }
+
+        pub fn adts_frame() -> Vec<u8> {
+            let length = 7 + 100;
+            let mut frame = vec![
+                0xff,
+                0xf1,
+                0x50,
+                0x80 | ((length >> 11) & 0x03) as u8,
+                ((length >> 3) & 0xff) as u8,
+                (((length & 0x07) << 5) | 0x1f) as u8,
+                0xfc,
+            ];
+            frame.extend((0..100).map(|i| i as u8));
+            frame
+        }
+
+        pub fn encrypt_adts(frame: &[u8]) -> Vec<u8> {
+            let mut data = frame.to_vec();
+            let body = &mut data[7..];
+            let end = body.len() - body.len() % 16;
+            let mut encryptor = CbcEncryptor::new();
+            for position in (16..end).step_by(16) {
+                encryptor.encrypt_block(&mut body[position..position + 16]);
+            }
+            data
+        }
+
+        pub fn video_pes(nal: &[u8]) -> Vec<u8> {
+            let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0x21, 0, 1, 0, 1];
+            pes.extend([0, 0, 0, 1, 0x09, 0xf0]);
+            pes.extend([0, 0, 0, 1]);
+            pes.extend(add_emulation_prevention(nal));
+            pes
+        }
+
+        pub fn audio_pes(frame: &[u8]) -> Vec<u8> {
+            let length = (8 + frame.len()) as u16;
+            let mut pes = vec![0, 0, 1, 0xc0];
+            pes.extend(length.to_be_bytes());
+            pes.extend([0x80, 0x80, 5, 0x21, 0, 1, 0, 1]);
+            pes.extend(frame);
+            pes
+        }
+
+        pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+            let mut crc = 0xffff_ffffu32;
+            for byte in data {
+                crc ^= (*byte as u32) << 24;
+                for _ in 0..8 {
+                    crc = if crc & 0x8000_0000 != 0 {
+                        (crc << 1) ^ 0x04c1_1db7
+                    } else {
+                        crc << 1
+                    };
+                }
+            }
+            crc
+        }
+
+        fn psi_packet(pid: u16, section: &[u8]) -> Vec<u8> {
+            let mut packet = vec![0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10, 0];
+            packet.extend(section);
+            packet.extend(crc32_mpeg2(section).to_be_bytes());
+            packet.resize(188, 0xff);
+            packet
+        }
+
+        pub fn pes_packets(pid: u16, pes: &[u8]) -> Vec<u8> {
+            let mut output = Vec::new();
+            for (counter, chunk) in pes.chunks(184).enumerate() {
+                let start = if counter == 0 { 0x40 } else { 0 };
+                let mut packet = vec![0x47, start | (pid >> 8) as u8, pid as u8];
+                if chunk.len() == 184 {
+                    packet.push(0x10 | counter as u8 & 0x0f);
+                } else {
+                    let stuffing = 184 - chunk.len();
+                    packet.push(0x30 | counter as u8 & 0x0f);
+                    packet.push((stuffing - 1) as u8);
+                    if stuffing > 1 {
+                        packet.push(0);
+                        packet.resize(4 + stuffing, 0xff);
+                    }
+                }
+                packet.extend(chunk);
+                output.extend(packet);
+            }
+            output
+        }
+
+        pub fn transport_stream(
+            video_type: u8,
+            audio_type: u8,
+            video: &[u8],
+            audio: &[u8],
+        ) -> Vec<u8> {
+            let pat = [0x00, 0xb0, 0x0d, 0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00];
+            let pmt = [
+                0x02, 0xb0, 0x17, 0, 1, 0xc1, 0, 0, 0xe1, 0x00, 0xf0, 0x00, video_type, 0xe1, 0x00,
+                0xf0, 0x00, audio_type, 0xe1, 0x01, 0xf0, 0x00,
+            ];
+            let mut output = psi_packet(0, &pat);
+            output.extend(psi_packet(0x1000, &pmt));
+            output.extend(pes_packets(VIDEO_PID, video));
+            output.extend(pes_packets(AUDIO_PID, audio));
+            output
+        }
+
+        // Concatenate payloads of a PID and check continuity counters
+        pub fn demux(data: &[u8], pid: u16) -> Vec<u8> {
+            let mut output = Vec::new();
+            let mut counter: Option<u8> = None;
+            for packet in data.chunks(188) {
+                let packet_pid = ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16;
+                if packet_pid != pid || packet[3] & 0x10 == 0 {
+                    continue;
+                }
+                let current = packet[3] & 0x0f;
+                if let Some(previous) = counter {
+                    assert_eq!(current, (previous + 1) & 0x0f);
+                }
+                counter = Some(current);
+                let offset = if packet[3] & 0x20 != 0 {
+                    5 + packet[4] as usize
+                } else {
+                    4
+                };
+                output.extend(&packet[offset..]);
+            }
+            output
+        }
+    }
+
+    #[test]
+    fn decrypt_sample_aes_transport_stream_work() {
+        use crate::sample_aes::{decrypt_sample_aes, CbcsPatterns};
+        use sample_aes_fixture::*;
+
+        let nal = nal_unit();
+        let frame = adts_frame();
+        let encrypted = transport_stream(
+            0xdb,
+            0xcf,
+            &video_pes(&encrypt_nal(&nal)),
+            &audio_pes(&encrypt_adts(&frame)),
+        );
+        let decrypted = decrypt_sample_aes(&encrypted, &KEY, &IV, &CbcsPatterns::new()).unwrap();
+        assert_eq!(decrypted.len() % 188, 0);
+        // Escaped clear NAL is longer, so an extra packet is needed
+        assert!(decrypted.len() > encrypted.len());
+        assert_eq!(demux(&decrypted, VIDEO_PID), video_pes(&nal));
+        assert_eq!(demux(&decrypted, AUDIO_PID), audio_pes(&frame));
+
+        // Stream types are restored and PMT CRC is still valid
+        let pmt = &decrypted[188 + 5..188 + 5 + 26];
+        assert_eq!(pmt[12], 0x1b);
+        assert_eq!(pmt[17], 0x0f);
+        assert_eq!(crc32_mpeg2(pmt), 0);
+    }
+
+    #[test]
+    fn decrypt_sample_aes_fmp4_work() {
+        use crate::remux::mp4_box;
+        use crate::sample_aes::{decrypt_sample_aes, CbcsPatterns};
+        use sample_aes_fixture::*;
+
+        // Video sample with 2 subsamples and audio-like sample without subsamples
+        let video: Vec<u8> = (0..400).map(|i| (i * 7) as u8).collect();
+        let audio: Vec<u8> = (0..90).map(|i| (i * 3) as u8).collect();
+        let subsamples = [(5usize, 192usize), (3, 200)];
+        let protected_ranges = [(5, 197), (200, 400)];
+
+        // Crypt then skip blocks in each range, the chain restarts for each range
+        let encrypt = |data: &[u8], ranges: &[(usize, usize)], crypt: usize, skip: usize| {
+            let mut data = data.to_vec();
+            for (start, end) in ranges {
+                let mut encryptor = CbcEncryptor::new();
+                for (block, position) in (*start..end - 15).step_by(16).enumerate() {
+                    if block % (crypt + skip) < crypt {
+                        encryptor.encrypt_block(&mut data[position..position + 16]);
+                    }
+                }
+            }
+            data
+        };
+        let encrypted_video = encrypt(&video, &protected_ranges, 1, 9);
+        let encrypted_audio = encrypt(&audio, &[(0, 90)], 1, 0);
+
+        let mut senc = vec![0, 0, 0, 2, 0, 0, 0, 2, 0, 2];
+        for (clear, protected) in subsamples {
+            senc.extend((clear as u16).to_be_bytes());
+            senc.extend((protected as u32).to_be_bytes());
+        }
+        senc.extend([0, 0]);
+        let build = |video: &[u8], audio: &[u8]| {
+            let tfhd = mp4_box(b"tfhd", &[0, 2, 0, 0, 0, 0, 0, 1]);
+            let senc = mp4_box(b"senc", &senc);
+            let trun_length = 8 + 20;
+            let traf_length = 8 + tfhd.len() + trun_length + senc.len();
+            let moof_length = 8 + 16 + traf_length;
+            let mut trun = vec![0, 0, 2, 1, 0, 0, 0, 2];
+            trun.extend(((moof_length + 8) as u32).to_be_bytes());
+            trun.extend((video.len() as u32).to_be_bytes());
+            trun.extend((audio.len() as u32).to_be_bytes());
+            let trun = mp4_box(b"trun", &trun);
+            let traf = mp4_box(b"traf", &[tfhd, trun, senc].concat());
+            let moof = mp4_box(b"moof", &[mp4_box(b"mfhd", &[0; 8]), traf].concat());
+            assert_eq!(moof.len(), moof_length);
+            let mut data = mp4_box(b"styp", b"msdh");
+            data.extend(moof);
+            data.extend(mp4_box(b"mdat", &[video, audio].concat()));
+            data
+        };
+
+        let encrypted = build(&encrypted_video, &encrypted_audio);
+        assert_ne!(encrypted, build(&video, &audio));
+        let decrypted = decrypt_sample_aes(&encrypted, &KEY, &IV, &CbcsPatterns::new()).unwrap();
+        assert_eq!(decrypted, build(&video, &audio));
+
+        // Pattern of the track from its tenc box
+        let patterns = CbcsPatterns::from([(1, (2, 8))]);
+        let encrypted = build(
+            &encrypt(&video, &protected_ranges, 2, 8),
+            &encrypt(&audio, &[(0, 90)], 2, 8),
+        );
+        let decrypted = decrypt_sample_aes(&encrypted, &KEY, &IV, &patterns).unwrap();
+        assert_eq!(decrypted, build(&video, &audio));
+
+        // Data offset of trun pointing before the start of the segment
+        let mut invalid = encrypted.clone();
+        let data_offset = 12 + 8 + 16 + 8 + 16 + 8 + 8;
+        assert_eq!(invalid[data_offset..data_offset + 4], 116u32.to_be_bytes());
+        invalid[data_offset..data_offset + 4].copy_from_slice(&(-1000i32).to_be_bytes());
+        assert!(decrypt_sample_aes(&invalid, &KEY, &IV, &CbcsPatterns::new()).is_err());
+
+        // 64 bit box size which overflows the position
+        let mut invalid = mp4_box(b"moof", &[0; 8]);
+        invalid.extend([0, 0, 0, 1]);
+        invalid.extend(b"mdat");
+        invalid.extend(u64::MAX.to_be_bytes());
+        assert!(decrypt_sample_aes(&invalid, &KEY, &IV, &CbcsPatterns::new()).is_ok());
+    }
+
+    #[test]
+    fn parse_cbcs_patterns_work() {
+        use crate::remux::mp4_box;
+        use crate::sample_aes::parse_cbcs_patterns;
+
+        let trak = |track_id: u32, entry: &[u8; 4], fields: usize, version: u8, pattern: u8| {
+            let mut tkhd = vec![0; 12];
+            tkhd.extend(track_id.to_be_bytes());
+            tkhd.extend([0; 8]);
+            let mut tenc = vec![version, 0, 0, 0, 0, pattern, 1, 16];
+            tenc.extend([0; 16]);
+            let schi = mp4_box(b"schi", &mp4_box(b"tenc", &tenc));
+            let sinf = mp4_box(b"sinf", &[mp4_box(b"frma", b"avc1"), schi].concat());
+            let entry = mp4_box(entry, &[vec![0; fields], sinf].concat());
+            let stsd = mp4_box(b"stsd", &[vec![0, 0, 0, 0, 0, 0, 0, 1], entry].concat());
+            let stbl = mp4_box(b"stbl", &stsd);
+            let mdia = mp4_box(b"mdia", &mp4_box(b"minf", &stbl));
+            mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat())
+        };
+        let init = |traks: &[Vec<u8>]| {
+            let mut data = mp4_box(b"ftyp", b"iso6");
+            data.extend(mp4_box(b"moov", &traks.concat()));
+            data
+        };
+
+        let patterns = parse_cbcs_patterns(&init(&[
+            trak(1, b"encv", 78, 1, 0x19),
+            trak(2, b"enca", 28, 1, 0x00),
+            trak(3, b"enca", 28, 0, 0x00),
+        ]))
+        .unwrap();
+        assert_eq!(patterns.get(&1), Some(&(1, 9)));
+        // 0:0 encrypts every block
+        assert_eq!(patterns.get(&2), Some(&(1, 0)));
+        assert_eq!(patterns.get(&3), None);
+        assert!(parse_cbcs_patterns(&init(&[trak(1, b"encv", 78, 1, 0x05)])).is_err());
+    }
+
+    #[test]
+    fn parse_sample_aes_key_work() {
+        let content = "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\",IV=0x01010101010101010101010101010101\n#EXTINF:1,\na.ts";
+        let playlist = parse_media_playlist(content, None).unwrap();
+        let key = playlist.segments[0].key.as_ref().unwrap();
+        assert_eq!(key.method, KeyMethod::SampleAes);
+        assert_eq!(key.iv, Some([1; 16]));
+    }
+
+    #[test]
+    fn parse_key_format_work() {
+        let fairplay = "#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://key\",KEYFORMAT=\"com.apple.streamingkeydelivery\",KEYFORMATVERSIONS=\"1\"\n";
+        let identity = "#EXT-X-KEY:METHOD=AES-128,URI=\"k\",KEYFORMAT=\"identity\"\n";
+        let content = format!("#EXTM3U\n{}{}#EXTINF:1,\na.ts\n", fairplay, identity);
+        let playlist = parse_media_playlist(&content, None).unwrap();
+        assert_eq!(playlist.segments[0].key.as_ref().unwrap().uri, "k");
+
+        let content = format!(
+            "#EXTM3U\n{}#EXTINF:1,\na.ts\n{}#EXTINF:1,\nb.ts\n",
+            identity, fairplay
+        );
+        match parse_media_playlist(&content, None) {
+            Err(e) => assert!(e.to_string().contains("com.apple.streamingkeydelivery")),
+            Ok(_) => panic!("FairPlay key is not supported"),
+        }
+    }
+
+    const FMP4_PLAYLIST: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MAP:URI="init.mp4",BYTERANGE="720@0"
+#EXTINF:6,
+seg-0.m4s
+#EXT-X-KEY:METHOD=AES-128,URI="key.bin",IV=0x0000000000000000000000000000000a
+#EXT-X-MAP:URI="init-2.mp4"
+#EXTINF:6,
+seg-1.m4s
+"#;
+
+    #[test]
+    fn parse_map_work() {
+        let base = Url::parse("https://example.com/cmaf/index.m3u8").unwrap();
+        let playlist = parse_media_playlist(FMP4_PLAYLIST, Some(&base)).unwrap();
+        let first = playlist.segments[0].init.as_ref().unwrap();
+        assert_eq!(first.uri, "https://example.com/cmaf/init.mp4");
+        assert_eq!(
+            first.byte_range,
+            Some(ByteRange {
+                length: 720,
+                offset: 0
+            })
+        );
+        assert_eq!(first.key, None);
+        let second = playlist.segments[1].init.as_ref().unwrap();
+        assert_eq!(second.uri, "https://example.com/cmaf/init-2.mp4");
+        assert_eq!(second.byte_range, None);
+        assert_eq!(
+            second.key.as_ref().unwrap().uri,
+            "https://example.com/cmaf/key.bin"
+        );
+    }
+
+    #[test]
+    fn slice_byte_range_work() {
+        let data = Bytes::from_static(b"0123456789");
+        let range = ByteRange {
+            length: 4,
+            offset: 3,
+        };
+        assert_eq!(slice_byte_range(data.clone(), &range).unwrap(), "3456");
+        let out_of_range = ByteRange {
+            length: 4,
+            offset: 8,
+        };
+        assert!(slice_byte_range(data.clone(), &out_of_range).is_err());
+        let overflow = ByteRange {
+            length: u64::MAX,
+            offset: 3,
+        };
+        assert!(slice_byte_range(data, &overflow).is_err());
+    }
+
+    const BYTERANGE_PLAYLIST: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:10,
+#EXT-X-BYTERANGE:75232@0
+video.ts
+#EXTINF:10,
+#EXT-X-BYTERANGE:82112
+video.ts
+#EXTINF:10,
+#EXT-X-BYTERANGE:69864@200000
+video.ts
+#EXTINF:10,
+other.ts
+";
+
+    #[test]
+    fn parse_byte_range_work() {
+        let playlist = parse_media_playlist(BYTERANGE_PLAYLIST, None).unwrap();
+        let ranges: Vec<_> = playlist
+            .segments
+            .iter()
+            .map(|s| s.byte_range.as_ref().map(|r| (r.length, r.offset)))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                Some((75232, 0)),
+                Some((82112, 75232)),
+                Some((69864, 200000)),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_byte_range_without_previous_fail() {
+        let content = "#EXTM3U\n#EXTINF:1,\n#EXT-X-BYTERANGE:100\na.ts";
+        assert!(parse_media_playlist(content, None).is_err());
+        let content = "#EXTM3U\n#EXTINF:1,\n#EXT-X-BYTERANGE:0@0\na.ts";
+        assert!(parse_media_playlist(content, None).is_err());
+        let content = "#EXTM3U\n#EXTINF:1,\n#EXT-X-BYTERANGE:2@18446744073709551615\na.ts";
+        assert!(parse_media_playlist(content, None).is_err());
+        let content = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"0@0\"\n#EXTINF:1,\na.ts";
+        assert!(parse_media_playlist(content, None).is_err());
+    }
+
+    #[test]
+    fn live_new_segments_work() {
+        use crate::live::new_segments;
+
+        let window = |first: u64| {
+            let mut content = format!("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:{}\n", first);
+            for sequence in first..first + 3 {
+                content.push_str(&format!("#EXTINF:4,\nseg-{}.ts\n", sequence));
+            }
+            parse_media_playlist(&content, None).unwrap()
+        };
+        let sequences = |segments: &[crate::playlist::MediaSegment]| -> Vec<u64> {
+            segments.iter().map(|s| s.sequence).collect()
+        };
+
+        let first = window(10);
+        assert_eq!(
+            sequences(new_segments(&first, None, None, 0.0)),
+            [10, 11, 12]
+        );
+        // Reloaded playlist slides by one segment
+        let reloaded = window(11);
+        assert_eq!(
+            sequences(new_segments(&reloaded, Some(12), None, 12.0)),
+            [13]
+        );
+        assert!(new_segments(&first, Some(12), None, 12.0).is_empty());
+        // Stop adding segments once the duration limit is reached
+        assert_eq!(
+            sequences(new_segments(&first, None, Some(6), 0.0)),
+            [10, 11]
+        );
+        assert!(new_segments(&reloaded, Some(12), Some(12), 12.0).is_empty());
+    }
+
+    // SPS of 1920x1080 baseline stream, coded as 1920x1088 with bottom cropping
+    fn h264_sps() -> Vec<u8> {
+        h264_sps_cropped("00101")
+    }
+
+    // Exp-Golomb coded bottom cropping in units of 2 lines
+    fn h264_sps_cropped(crop_bottom: &str) -> Vec<u8> {
+        let bits = [
+            "1",
+            "1",
+            "1",
+            "1",
+            "010",
+            "0",
+            "0000001111000",
+            "0000001000100",
+            "1",
+            "1",
+            "1",
+            "1",
+            "1",
+            "1",
+            crop_bottom,
+            "0",
+            "1",
+        ]
+        .concat();
+        let mut sps = vec![0x67, 66, 0, 40];
+        for chunk in bits.as_bytes().chunks(8) {
+            let mut byte = 0u8;
+            for (i, bit) in chunk.iter().enumerate() {
+                byte |= (bit - b'0') << (7 - i);
+            }
+            sps.push(byte);
+        }
+        sps
+    }
+
+    #[test]
+    fn sps_dimensions_work() {
+        use crate::remux::sps_dimensions;
+
+        assert_eq!(sps_dimensions(&h264_sps()), Some((1920, 1080)));
+        assert_eq!(sps_dimensions(&[0x67, 66]), None);
+        // 1200 cropped lines of 1088 coded lines
+        assert_eq!(
+            sps_dimensions(&h264_sps_cropped("0000000001001011001")),
+            None
+        );
+    }
+
+    #[test]
+    fn remux_ts_to_mp4_work() {
+        use crate::remux::remux_ts_to_mp4;
+        use sample_aes_fixture::*;
+
+        let sps = h264_sps();
+        let pps = [0x68, 0xce, 0x3c, 0x80];
+        let idr = nal_unit();
+        let mut video = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0x21, 0, 1, 0, 1];
+        for nal in [&sps[..], &[0x09, 0xf0], &pps, &idr] {
+            video.extend([0, 0, 0, 1]);
+            video.extend(nal);
+        }
+        let mut frames = adts_frame();
+        frames.extend(adts_frame());
+        let mut stream = transport_stream(0x1b, 0x0f, &video, &audio_pes(&frames));
+        // Next audio PES starts 1 second later
+        let mut late_audio = audio_pes(&adts_frame());
+        late_audio[9..14].copy_from_slice(&[0x21, 0, 0x05, 0xbf, 0x21]);
+        stream.extend(pes_packets(AUDIO_PID, &late_audio));
+
+        let dir = test_dir("remux");
+        let input = dir.join("joined.ts");
+        let output = dir.join("output.mp4");
+        std::fs::write(&input, stream).unwrap();
+        remux_ts_to_mp4(&input, &output).unwrap();
+        let data = std::fs::read(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let find = |kind: &[u8], from: usize| -> usize {
+            from + data[from..]
+                .windows(4)
+                .position(|w| w == kind)
+                .expect("box is missing")
+        };
+        assert_eq!(&data[4..8], b"ftyp");
+        let mdat = find(b"mdat", 0);
+        let mdat_size = u64::from_be_bytes(data[mdat + 4..mdat + 12].try_into().unwrap());
+        assert_eq!(&data[mdat + mdat_size as usize - 4..][4..8], b"moov");
+
+        // Video sample is the length prefixed IDR unit without parameter sets,
+        // written after the first audio PES which ends earlier
+        let sample = [&(idr.len() as u32).to_be_bytes()[..], &idr].concat();
+        let video_chunk = mdat + 12 + 2 * 100;
+        assert_eq!(&data[video_chunk..video_chunk + sample.len()], &sample[..]);
+
+        // Video track then audio track with two ADTS frames without headers
+        let avcc = find(b"avcC", mdat);
+        assert_eq!(&data[avcc + 12..avcc + 12 + sps.len()], &sps[..]);
+        let avc1 = find(b"avc1", mdat);
+        assert_eq!(data[avc1 + 28..avc1 + 32], [0x07, 0x80, 0x04, 0x38]);
+        let video_stsz = find(b"stsz", mdat);
+        assert_eq!(data[video_stsz + 12..video_stsz + 16], 1u32.to_be_bytes());
+        let audio_stsz = find(b"stsz", video_stsz + 4);
+        assert_eq!(data[audio_stsz + 12..audio_stsz + 16], 3u32.to_be_bytes());
+        assert_eq!(data[audio_stsz + 16..audio_stsz + 20], 100u32.to_be_bytes());
+        // Second frame lasts until the late PES, 87911 ticks at 44.1 kHz
+        let audio_stts = find(b"stts", video_stsz + 4);
+        let entries: Vec<u32> = data[audio_stts + 8..audio_stts + 36]
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(entries, [3, 1, 1024, 1, 43076, 1, 1024]);
+        let esds = find(b"esds", mdat);
+        assert!(data[esds..].windows(4).any(|w| w == [0x05, 2, 0x12, 0x10]));
+    }
+
+    #[test]
+    fn job_manifest_resume_work() {
+        use crate::manifest::{checksum, JobManifest, SegmentJob, SegmentStatus};
+
+        let content = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:7\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x000102030405060708090a0b0c0d0e0f\n\
+            #EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXTINF:4,\nmain.mp4\n#EXTINF:4,\nmain.mp4\n#EXT-X-ENDLIST\n";
+        let base_url = Url::parse("https://example.com/video/").unwrap();
+        let playlist = parse_media_playlist(content, Some(&base_url)).unwrap();
+        let dir = format!("{}/", test_dir("manifest").to_string_lossy());
+
+        let data = b"segment data";
+        std::fs::write(format!("{}0.m4s", dir), data).unwrap();
+        let job = |index: usize, status: SegmentStatus, sha256: Option<String>| SegmentJob {
+            file_name: format!("{}.m4s", index),
+            status,
+            size: sha256.as_ref().map(|_| data.len() as u64),
+            sha256,
+            segment: playlist.segments[index].clone(),
+        };
+        let manifest = JobManifest {
+            playlist_url: Some("https://example.com/video/index.m3u8".to_string()),
+            segments: vec![
+                job(0, SegmentStatus::Completed, Some(checksum(data))),
+                job(1, SegmentStatus::Failed, None),
+                job(1, SegmentStatus::Pending, None),
+            ],
+        };
+        manifest.save(&dir).unwrap();
+        let loaded = JobManifest::load(&dir).unwrap();
+
+        assert_eq!(loaded.playlist_url, manifest.playlist_url);
+        assert_eq!(loaded.segments[0].segment, playlist.segments[0]);
+        assert_eq!(loaded.segments[1].segment, playlist.segments[1]);
+        assert_eq!(loaded.failed_count(), 1);
+        assert!(loaded.is_completed(0, &playlist.segments[0], &dir));
+        assert!(!loaded.is_completed(1, &playlist.segments[1], &dir));
+        // Segment list changed since the manifest was written
+        assert!(!loaded.is_completed(0, &playlist.segments[1], &dir));
+        // Completed file is modified after download
+        std::fs::write(format!("{}0.m4s", dir), b"partial").unwrap();
+        assert!(!loaded.is_completed(0, &playlist.segments[0], &dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_pool_limit_work() {
+        use crate::pool::{run_pool, OrderedProgress};
+        use std::time::Duration;
+
+        // Highest number of tasks running at once for each host and in total
+        async fn run(jobs: usize, host_jobs: Option<usize>) -> (Vec<usize>, [usize; 3]) {
+            let running: [Arc<AtomicUsize>; 3] = Default::default();
+            let peaks: [Arc<AtomicUsize>; 3] = Default::default();
+            let tasks: Vec<_> = (0..12)
+                .map(|index| {
+                    let host = index % 2;
+                    let (running, peak) = (running.clone(), peaks.clone());
+                    let url = format!("https://host{}.example.com/{}.ts", host, index);
+                    let task = async move {
+                        for counter in [host, 2] {
+                            let now = running[counter].fetch_add(1, Ordering::SeqCst) + 1;
+                            peak[counter].fetch_max(now, Ordering::SeqCst);
+                        }
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        for counter in [host, 2] {
+                            running[counter].fetch_sub(1, Ordering::SeqCst);
+                        }
+                        index
+                    };
+                    (index, url, task)
+                })
+                .collect();
+            let mut order = Vec::new();
+            run_pool(tasks, jobs, host_jobs, |index, result| {
+                assert_eq!(result.unwrap(), index);
+                order.push(index);
+            })
+            .await;
+            (order, peaks.map(|p| p.load(Ordering::SeqCst)))
+        }
+
+        let (order, peaks) = run(1, None).await;
+        assert_eq!(order, (0..12).collect::<Vec<_>>());
+        assert_eq!(peaks, [1, 1, 1]);
+        let (order, peaks) = run(6, Some(2)).await;
+        assert_eq!(order.len(), 12);
+        assert!(peaks[0] <= 2 && peaks[1] <= 2);
+        let (_, peaks) = run(4, None).await;
+        assert!(peaks[2] <= 4 && peaks[2] > 1);
+
+        let mut progress = OrderedProgress::build(3);
+        progress.complete(1);
+        assert_eq!((progress.completed, progress.in_order), (1, 0));
+        progress.complete(0);
+        progress.complete(0);
+        assert_eq!((progress.completed, progress.in_order), (2, 2));
+    }
+
+    #[test]
+    fn har_media_work() {
+        let entry = |url: &str, mime: &str| {
+            format!(
+                r#"{{"request": {{"method": "GET", "url": "{}"}},
+                  "response": {{"status": 200, "content": {{"mimeType": "{}"}}}}}}"#,
+                url, mime
+            )
+        };
+        let har =
+            |entries: Vec<String>| format!(r#"{{"log": {{"entries": [{}]}}}}"#, entries.join(","));
+
+        let content = har(vec![
+            entry("https://example.com/watch", "text/html"),
+            entry("https://cdn.example.com/seg-0.ts", "video/mp2t"),
+            entry("https://cdn.example.com/seg-1?id=2", "video/mp2t"),
+            entry("https://cdn.example.com/seg-0.ts", "video/mp2t"),
+        ]);
+        let (request, link_list) = har_media(parse_har(&content).unwrap()).unwrap();
+        assert_eq!(request.url, "https://cdn.example.com/seg-0.ts");
+        let uris: Vec<_> = link_list
+            .unwrap()
+            .segments
+            .into_iter()
+            .map(|s| s.uri)
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "https://cdn.example.com/seg-0.ts",
+                "https://cdn.example.com/seg-1?id=2"
+            ]
+        );
+
+        let content = har(vec![
+            entry("https://cdn.example.com/seg-0.ts", "video/mp2t"),
+            entry(
+                "https://cdn.example.com/master",
+                "application/vnd.apple.mpegurl",
+            ),
+        ]);
+        let (request, link_list) = har_media(parse_har(&content).unwrap()).unwrap();
+        assert_eq!(request.url, "https://cdn.example.com/master");
+        assert!(link_list.is_none());
+
+        let content = har(vec![entry("https://example.com/", "text/html")]);
+        assert!(har_media(parse_har(&content).unwrap()).is_none());
+    }
 }
@@ -0,0 +1,139 @@
+// MPEG-TS packet, PSI and H.264 elementary stream helpers
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_SYNC_BYTE: u8 = 0x47;
+
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_AAC: u8 = 0x0f;
+
+pub fn is_transport_stream(data: &[u8]) -> bool {
+    !data.is_empty()
+        && data.len().is_multiple_of(TS_PACKET_SIZE)
+        && data
+            .iter()
+            .step_by(TS_PACKET_SIZE)
+            .all(|b| *b == TS_SYNC_BYTE)
+}
+
+pub fn packet_pid(packet: &[u8]) -> u16 {
+    (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16
+}
+
+pub fn packet_payload_offset(packet: &[u8]) -> Option<usize> {
+    let control = (packet[3] >> 4) & 0x3;
+    if control & 0x1 == 0 {
+        return None;
+    }
+    let offset = if control & 0x2 != 0 {
+        5 + packet[4] as usize
+    } else {
+        4
+    };
+    if offset >= TS_PACKET_SIZE {
+        None
+    } else {
+        Some(offset)
+    }
+}
+
+pub fn parse_pat(payload: &[u8]) -> Vec<u16> {
+    let section = match psi_section(payload) {
+        Some(s) => s,
+        None => return Vec::new(),
+    };
+    // Program loop between the 8 byte header and 4 byte CRC
+    section[8..section.len() - 4]
+        .chunks_exact(4)
+        .filter(|p| p[0] != 0 || p[1] != 0)
+        .map(|p| (((p[2] & 0x1f) as u16) << 8) | p[3] as u16)
+        .collect()
+}
+
+// Return section from table_id to the end of CRC
+pub fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    if section.len() < 3 {
+        return None;
+    }
+    let length = ((((section[1] & 0x0f) as usize) << 8) | section[2] as usize) + 3;
+    if length < 12 {
+        return None;
+    }
+    section.get(..length)
+}
+
+pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Return stream type and PID of each elementary stream in a PMT section
+pub fn parse_pmt(section: &[u8]) -> Vec<(u8, u16)> {
+    let mut streams = Vec::new();
+    if section.len() < 16 {
+        return streams;
+    }
+    let program_info_length = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+    let mut position = 12 + program_info_length;
+    while position + 5 <= section.len() - 4 {
+        let pid = (((section[position + 1] & 0x1f) as u16) << 8) | section[position + 2] as u16;
+        streams.push((section[position], pid));
+        let es_info_length =
+            (((section[position + 3] & 0x0f) as usize) << 8) | section[position + 4] as usize;
+        position += 5 + es_info_length;
+    }
+    streams
+}
+
+// Return NAL unit ranges without start codes and trailing zero bytes
+pub fn nal_units(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index] == 0 && data[index + 1] == 0 && data[index + 2] == 1 {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    let mut units = Vec::new();
+    for (position, start) in starts.iter().enumerate() {
+        let mut end = match starts.get(position + 1) {
+            Some(next) => next - 3,
+            None => data.len(),
+        };
+        while end > *start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > *start {
+            units.push((*start, end));
+        }
+    }
+    units
+}
+
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for byte in data {
+        if zeros >= 2 && *byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+        output.push(*byte);
+    }
+    output
+}
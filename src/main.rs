@@ -2,5 +2,8 @@ use saidl_cli as cli;
 
 #[tokio::main]
 async fn main() {
-    cli::run().await;
+    let code = cli::run().await;
+    if code != 0 {
+        std::process::exit(code);
+    }
 }